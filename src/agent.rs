@@ -0,0 +1,280 @@
+use crate::vault::lock_memory;
+use color_eyre::eyre::{eyre, Result};
+use std::{
+    env,
+    ffi::OsStr,
+    fs,
+    io::{ErrorKind, Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, MetadataExt},
+        io::AsRawFd,
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+use zeroize::Zeroizing;
+
+/// How long a client waits for the agent to answer
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Socket path used when none is configured, inside a directory only accessible by the user
+pub fn default_socket_path() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("aegis-agent.sock"),
+        None => fallback_dir().join("agent.sock"),
+    }
+}
+
+/// Directory of the socket in the shared temporary directory, when there is no runtime directory
+fn fallback_dir() -> PathBuf {
+    env::temp_dir().join(format!("aegis-agent-{}", uid()))
+}
+
+fn uid() -> u32 {
+    // SAFETY: getuid has no preconditions and cannot fail
+    unsafe { libc::getuid() }
+}
+
+/// Make sure that the fallback directory of the socket is a real directory that only the user
+/// can access, as anyone can create it in the shared temporary directory first
+fn check_socket_dir(socket_path: &Path) -> Result<()> {
+    let dir = fallback_dir();
+    if socket_path.parent() != Some(dir.as_path()) {
+        return Ok(());
+    }
+    check_private_dir(&dir)
+}
+
+fn check_private_dir(dir: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() || metadata.uid() != uid() || metadata.mode() & 0o777 != 0o700 {
+        return Err(eyre!(
+            "{} must be a directory only accessible by the user",
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+/// User ID of the process on the other end of the socket
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn peer_uid(stream: &UnixStream) -> Result<u32> {
+    let mut cred = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut len = size_of::<libc::ucred>() as libc::socklen_t;
+    // SAFETY: cred and len describe a writable ucred of the size SO_PEERCRED fills in
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            (&mut cred as *mut libc::ucred).cast(),
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(cred.uid)
+}
+
+/// User ID of the process on the other end of the socket
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn peer_uid(stream: &UnixStream) -> Result<u32> {
+    let mut uid = 0;
+    let mut gid = 0;
+    // SAFETY: uid and gid are writable and the descriptor belongs to the open stream
+    if unsafe { libc::getpeereid(stream.as_raw_fd(), &mut uid, &mut gid) } != 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(uid)
+}
+
+/// Make sure that the other end of the socket belongs to the user, as the key must not be handed
+/// to or asked from another user, whatever the permissions of the socket are
+fn check_peer(stream: &UnixStream) -> Result<()> {
+    if peer_uid(stream)? != uid() {
+        return Err(eyre!("The other end of the socket belongs to another user"));
+    }
+    Ok(())
+}
+
+/// Create the socket with permissions only for the user from the start, instead of changing
+/// them after it could already be connected to
+fn bind(socket_path: &Path) -> Result<UnixListener> {
+    // SAFETY: umask has no preconditions, the agent has no other threads creating files yet
+    let previous = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(socket_path);
+    // SAFETY: as above
+    unsafe { libc::umask(previous) };
+    Ok(listener?)
+}
+
+/// Ask a running agent for the master key of the vault at `vault_file`
+///
+/// Returns `None` when no agent is running or the agent holds the key of another vault.
+pub fn request_master_key(socket_path: &Path, vault_file: &Path) -> Option<Zeroizing<Vec<u8>>> {
+    check_socket_dir(socket_path).ok()?;
+    let vault_file = fs::canonicalize(vault_file).ok()?;
+    let mut stream = UnixStream::connect(socket_path).ok()?;
+    check_peer(&stream).ok()?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).ok()?;
+    writeln!(stream, "{}", vault_file.display()).ok()?;
+    let response = read_line(&mut stream).ok()?;
+    hex::decode(response.trim_ascii())
+        .ok()
+        .map(Zeroizing::new)
+        .filter(|key| !key.is_empty())
+}
+
+/// Keep the master key of the vault at `vault_file` in locked memory and hand it out to
+/// clients on the socket until no client asked for it during `idle_timeout`
+pub fn run(
+    socket_path: &Path,
+    vault_file: &Path,
+    master_key: Zeroizing<Vec<u8>>,
+    idle_timeout: Duration,
+) -> Result<()> {
+    let vault_file = fs::canonicalize(vault_file)?;
+    if let Some(dir) = socket_path.parent().filter(|dir| *dir == fallback_dir()) {
+        match fs::DirBuilder::new().mode(0o700).create(dir) {
+            Err(e) if e.kind() != ErrorKind::AlreadyExists => return Err(e.into()),
+            _ => {}
+        }
+    }
+    check_socket_dir(socket_path)?;
+    if socket_path.exists() {
+        if UnixStream::connect(socket_path).is_ok() {
+            return Err(eyre!(
+                "An agent is already listening on {}",
+                socket_path.display()
+            ));
+        }
+        // Left behind by an agent that was killed
+        fs::remove_file(socket_path)?;
+    }
+    let listener = bind(socket_path)?;
+    listener.set_nonblocking(true)?;
+    if !lock_memory(&master_key) {
+        eprintln!("Failed to lock the master key in memory");
+    }
+
+    println!(
+        "Agent listening on {} (exits after {}s idle)",
+        socket_path.display(),
+        idle_timeout.as_secs()
+    );
+    println!("export AEGIS_AGENT_SOCKET={}", socket_path.display());
+
+    let mut last_request = Instant::now();
+    while last_request.elapsed() < idle_timeout {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Ok(true) = serve(stream, &vault_file, &master_key) {
+                    last_request = Instant::now();
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => eprintln!("Failed to accept agent connection: {}", e),
+        }
+    }
+
+    fs::remove_file(socket_path)?;
+    Ok(())
+}
+
+/// Longest line read from the socket, enough for a path or a hex encoded key
+const MAX_LINE: usize = 4096;
+
+/// Read a line without the newline byte by byte, so that no buffer outside of the returned one
+/// holds a copy of the key
+fn read_line(stream: &mut impl Read) -> Result<Zeroizing<Vec<u8>>> {
+    let mut line = Zeroizing::new(Vec::with_capacity(MAX_LINE));
+    let mut byte = [0u8];
+    while line.len() < MAX_LINE {
+        if stream.read(&mut byte)? == 0 || byte[0] == b'\n' {
+            return Ok(line);
+        }
+        line.push(byte[0]);
+    }
+    Err(eyre!("Line too long"))
+}
+
+/// Answer a single client, returning whether it asked for the key of our vault
+fn serve(stream: UnixStream, vault_file: &Path, master_key: &[u8]) -> Result<bool> {
+    check_peer(&stream)?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    let request = read_line(&mut &stream)?;
+    let matches = Path::new(OsStr::from_bytes(&request)) == vault_file;
+    let response = Zeroizing::new(if matches {
+        hex::encode(master_key)
+    } else {
+        String::new()
+    });
+    writeln!(&stream, "{}", response.as_str())?;
+    Ok(matches)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn read_line_stops_at_newline() {
+        let mut input: &[u8] = b"00ff\nrest";
+        assert_eq!(read_line(&mut input).unwrap().as_slice(), b"00ff");
+        assert_eq!(read_line(&mut input).unwrap().as_slice(), b"rest");
+    }
+
+    #[test]
+    fn socket_dir_must_be_private() {
+        let base = env::temp_dir().join(format!("aegis-agent-test-{}", std::process::id()));
+        let dir = base.join("private");
+        let link = base.join("link");
+        fs::create_dir_all(&base).unwrap();
+        fs::DirBuilder::new().mode(0o700).create(&dir).unwrap();
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+
+        assert!(check_private_dir(&dir).is_ok());
+        assert!(check_private_dir(&link).is_err());
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(check_private_dir(&dir).is_err());
+        assert!(check_private_dir(&base.join("missing")).is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn serves_the_key_to_the_user() {
+        let (client, agent) = UnixStream::pair().unwrap();
+        assert_eq!(peer_uid(&client).unwrap(), uid());
+        writeln!(&client, "/vault.json").unwrap();
+        assert!(serve(agent, Path::new("/vault.json"), &[0xab, 0xcd]).unwrap());
+        assert_eq!(read_line(&mut &client).unwrap().as_slice(), b"abcd");
+    }
+
+    #[test]
+    fn socket_is_private_when_bound() {
+        let path = env::temp_dir().join(format!("aegis-agent-bind-{}.sock", std::process::id()));
+        let listener = bind(&path).unwrap();
+        let mode = fs::metadata(&path).unwrap().mode() & 0o777;
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(mode, 0o600);
+    }
+
+    #[test]
+    fn read_line_rejects_long_lines() {
+        let long = vec![b'a'; MAX_LINE + 1];
+        assert!(read_line(&mut long.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+use arboard::Clipboard;
+#[cfg(target_os = "linux")]
+use arboard::{ClearExtLinux, GetExtLinux, LinuxClipboardKind, SetExtLinux};
+use clap::ValueEnum;
+#[cfg(not(target_os = "linux"))]
+use color_eyre::eyre::eyre;
+use color_eyre::eyre::Result;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+/// Where the code of the selected entry goes
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Copy the displayed codes to the clipboard
+    Clipboard,
+    /// Copy the displayed codes to the primary selection, pasted with the middle mouse button
+    /// (Linux)
+    Primary,
+    /// Only display the codes
+    Off,
+    /// Print the code once on stdout instead of displaying it, like over SSH
+    Stdout,
+}
+
+/// Clipboard the displayed codes are copied to, which is cleared again once the codes are no
+/// longer needed
+pub struct CodeClipboard {
+    /// `None` when copying is off or there is no clipboard, like over SSH
+    clipboard: Option<Clipboard>,
+    #[cfg(target_os = "linux")]
+    selection: LinuxClipboardKind,
+    /// How long codes are kept in the clipboard, `None` to keep them until cleared
+    clear_after: Option<Duration>,
+    /// Code that was copied last
+    code: Option<Zeroizing<String>>,
+    copied_at: Option<Instant>,
+}
+
+impl CodeClipboard {
+    pub fn new(mode: Mode, clear_after: Option<Duration>) -> Result<Self> {
+        #[cfg(not(target_os = "linux"))]
+        if mode == Mode::Primary {
+            return Err(eyre!("The primary selection is only available on Linux"));
+        }
+        let clipboard = match mode {
+            Mode::Clipboard | Mode::Primary => Clipboard::new().ok(),
+            Mode::Off | Mode::Stdout => None,
+        };
+        Ok(Self {
+            clipboard,
+            #[cfg(target_os = "linux")]
+            selection: match mode {
+                Mode::Primary => LinuxClipboardKind::Primary,
+                _ => LinuxClipboardKind::Clipboard,
+            },
+            clear_after,
+            code: None,
+            copied_at: None,
+        })
+    }
+
+    /// Copy the code, keeping it in the clipboard for the configured time
+    pub fn copy(&mut self, code: &str) -> Result<()> {
+        let Some(clipboard) = self.clipboard.as_mut() else {
+            return Ok(());
+        };
+        #[cfg(target_os = "linux")]
+        clipboard.set().clipboard(self.selection).text(code)?;
+        #[cfg(not(target_os = "linux"))]
+        clipboard.set_text(code)?;
+        self.code = Some(Zeroizing::new(code.to_owned()));
+        self.copied_at = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Replace the copied code with the next code of the same entry, unless the time to keep
+    /// codes in the clipboard has run out or something else was copied since
+    pub fn refresh(&mut self, code: &str) -> Result<()> {
+        let copied_at = self.copied_at;
+        if copied_at.is_none() || self.timed_out() {
+            return Ok(());
+        }
+        if !self.contains_code() {
+            // Stop refreshing, the clipboard belongs to the user again
+            self.code = None;
+            self.copied_at = None;
+            return Ok(());
+        }
+        self.copy(code)?;
+        // The time to keep codes still counts from the copy of the first one
+        self.copied_at = copied_at;
+        Ok(())
+    }
+
+    /// Whether codes are copied at all
+    pub fn is_available(&self) -> bool {
+        self.clipboard.is_some()
+    }
+
+    /// Clear the clipboard once the time to keep codes in it has run out
+    pub fn clear_if_timed_out(&mut self) -> Result<()> {
+        if self.timed_out() {
+            self.clear()?;
+        }
+        Ok(())
+    }
+
+    /// Clear the clipboard if it still contains our code, leaving anything copied since alone
+    pub fn clear(&mut self) -> Result<()> {
+        let contains_code = self.contains_code();
+        self.code = None;
+        let Some(clipboard) = self.clipboard.as_mut().filter(|_| contains_code) else {
+            return Ok(());
+        };
+        #[cfg(target_os = "linux")]
+        clipboard.clear_with().clipboard(self.selection)?;
+        #[cfg(not(target_os = "linux"))]
+        clipboard.clear()?;
+        Ok(())
+    }
+
+    /// Whether the clipboard still contains the code that was copied last
+    fn contains_code(&mut self) -> bool {
+        let (Some(clipboard), Some(code)) = (self.clipboard.as_mut(), self.code.as_ref()) else {
+            return false;
+        };
+        #[cfg(target_os = "linux")]
+        let contents = clipboard.get().clipboard(self.selection).text();
+        #[cfg(not(target_os = "linux"))]
+        let contents = clipboard.get_text();
+        contents
+            .ok()
+            .map(Zeroizing::new)
+            .is_some_and(|contents| contents == *code)
+    }
+
+    fn timed_out(&self) -> bool {
+        match (self.clear_after, self.copied_at) {
+            (Some(clear_after), Some(copied_at)) => copied_at.elapsed() >= clear_after,
+            _ => false,
+        }
+    }
+}
+
+impl Drop for CodeClipboard {
+    fn drop(&mut self) {
+        // Also clear when the display loop is left with an error
+        let _ = self.clear();
+    }
+}
@@ -0,0 +1,354 @@
+use color_eyre::eyre::Result;
+use console::{pad_str, truncate_str, Alignment, Key, Style, Term};
+use std::{
+    collections::HashMap,
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+use zeroize::Zeroizing;
+
+use crate::clipboard::{CodeClipboard, Mode};
+use crate::otp::{
+    calculate_remaining_time, generate_otp, Entry, EntryInfo, EntryInfoMotp, EntryInfoYandex,
+};
+use crate::vault::Vault;
+use crate::{next_otp, remaining_time_style, ClipboardOptions};
+
+/// How often the codes and countdown bars are redrawn
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Width of the countdown bars in characters
+const BAR_WIDTH: usize = 10;
+
+/// Width of the code column, enough for the longest codes
+const CODE_WIDTH: usize = 10;
+
+/// Lines of the screen taken by the header and the footer
+const CHROME_LINES: usize = 2;
+
+/// What a key press asks the dashboard to do
+enum Action {
+    None,
+    Copy,
+    Quit,
+}
+
+/// Filter, highlight and scroll position of the table between redraws
+struct Dashboard<'a> {
+    entries: &'a mut [Entry],
+    filter: String,
+    /// Indices of the entries matching the filter
+    visible: Vec<usize>,
+    /// Position of the highlighted entry in `visible`
+    selected: usize,
+    /// Position in `visible` of the first entry on screen
+    scroll: usize,
+    /// Codes of HOTP entries generated on request, by entry index
+    hotp_codes: HashMap<usize, Zeroizing<String>>,
+    /// Message shown in the footer until the next key press
+    status: Option<String>,
+}
+
+/// Show the codes of all entries in a live table that is filtered by typing, until Escape is
+/// pressed
+///
+/// The table is drawn on stderr, so that `--clipboard stdout` can print the chosen code on
+/// stdout.
+pub fn run(
+    vault: &mut Vault,
+    entries: &mut [Entry],
+    clipboard_options: &ClipboardOptions,
+) -> Result<()> {
+    let mut clipboard =
+        CodeClipboard::new(clipboard_options.mode, clipboard_options.clear_after())?;
+    let term = Term::buffered_stderr();
+    term.hide_cursor()?;
+    term.clear_screen()?;
+    let result = show(
+        &term,
+        vault,
+        Dashboard::new(entries),
+        &mut clipboard,
+        clipboard_options.mode,
+    );
+    clipboard.clear()?;
+    term.clear_screen()?;
+    term.show_cursor()?;
+    term.flush()?;
+    if let Some(code) = result? {
+        println!("{}", code.as_str());
+    }
+    Ok(())
+}
+
+/// Redraw the dashboard and handle key presses until it is left, returning the code to print
+/// with `--clipboard stdout`
+fn show(
+    term: &Term,
+    vault: &mut Vault,
+    mut dashboard: Dashboard,
+    clipboard: &mut CodeClipboard,
+    mode: Mode,
+) -> Result<Option<Zeroizing<String>>> {
+    let (tx, rx) = mpsc::channel();
+    // Spawn a thread to listen for key presses
+    thread::spawn(move || {
+        let term = Term::stderr();
+        while let Ok(key) = term.read_key() {
+            if tx.send(key).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut size = term.size();
+    loop {
+        if term.size() != size {
+            // Start over after a resize, as the terminal may have reflowed the old table
+            size = term.size();
+            term.clear_screen()?;
+        }
+        clipboard.clear_if_timed_out()?;
+        dashboard.draw(term)?;
+
+        let key = match rx.recv_timeout(REFRESH_INTERVAL) {
+            Ok(key) => key,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => return Ok(None),
+        };
+        let page = (size.0 as usize).saturating_sub(CHROME_LINES).max(1);
+        match dashboard.handle_key(key, page) {
+            Action::None => {}
+            Action::Quit => return Ok(None),
+            Action::Copy => {
+                let Some((code, label)) = dashboard.selected_code(vault)? else {
+                    continue;
+                };
+                if mode == Mode::Stdout {
+                    return Ok(Some(code));
+                }
+                dashboard.status = Some(if clipboard.is_available() {
+                    clipboard.copy(&code)?;
+                    format!("Copied the code of {}", label)
+                } else {
+                    "No clipboard to copy to".to_string()
+                });
+            }
+        }
+    }
+}
+
+impl<'a> Dashboard<'a> {
+    fn new(entries: &'a mut [Entry]) -> Self {
+        let visible = (0..entries.len()).collect();
+        Self {
+            entries,
+            filter: String::new(),
+            visible,
+            selected: 0,
+            scroll: 0,
+            hotp_codes: HashMap::new(),
+            status: None,
+        }
+    }
+
+    /// Keep the entries whose issuer or name contain every word of the filter
+    fn apply_filter(&mut self) {
+        let filter = self.filter.to_lowercase();
+        let words: Vec<&str> = filter.split_whitespace().collect();
+        self.visible = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| {
+                let label = label(entry).to_lowercase();
+                words.iter().all(|word| label.contains(word))
+            })
+            .map(|(index, _)| index)
+            .collect();
+        self.selected = 0;
+        self.scroll = 0;
+    }
+
+    fn handle_key(&mut self, key: Key, page: usize) -> Action {
+        self.status = None;
+        let last = self.visible.len().saturating_sub(1);
+        match key {
+            Key::Escape if !self.filter.is_empty() => {
+                self.filter.clear();
+                self.apply_filter();
+            }
+            Key::Escape | Key::CtrlC => return Action::Quit,
+            Key::Enter => return Action::Copy,
+            Key::ArrowUp | Key::BackTab => self.selected = self.selected.saturating_sub(1),
+            Key::ArrowDown | Key::Tab => self.selected = (self.selected + 1).min(last),
+            Key::PageUp => self.selected = self.selected.saturating_sub(page),
+            Key::PageDown => self.selected = (self.selected + page).min(last),
+            Key::Home => self.selected = 0,
+            Key::End => self.selected = last,
+            Key::Backspace => {
+                self.filter.pop();
+                self.apply_filter();
+            }
+            Key::Char(c) if !c.is_control() => {
+                self.filter.push(c);
+                self.apply_filter();
+            }
+            _ => {}
+        }
+        Action::None
+    }
+
+    /// Code and label of the highlighted entry, generating the next code of HOTP entries
+    fn selected_code(&mut self, vault: &mut Vault) -> Result<Option<(Zeroizing<String>, String)>> {
+        let Some(&index) = self.visible.get(self.selected) else {
+            return Ok(None);
+        };
+        let entry = &mut self.entries[index];
+        if matches!(entry.info.pin_mut(), Some(None)) {
+            self.status =
+                Some("No PIN stored for this entry, select it without the dashboard".into());
+            return Ok(None);
+        }
+        let code = next_otp(vault, entry)?;
+        if let EntryInfo::Hotp(_) = entry.info {
+            self.hotp_codes.insert(index, code.clone());
+        }
+        Ok(Some((code, label(entry))))
+    }
+
+    fn draw(&mut self, term: &Term) -> Result<()> {
+        let (height, width) = term.size();
+        let (height, width) = (height as usize, width as usize);
+        let rows = height.saturating_sub(CHROME_LINES);
+        if self.selected < self.scroll {
+            self.scroll = self.selected;
+        } else if rows > 0 && self.selected >= self.scroll + rows {
+            self.scroll = self.selected + 1 - rows;
+        }
+        // Stay clear of the last column, where terminals wrap
+        let width = width.saturating_sub(1);
+
+        let mut lines = vec![Zeroizing::new(
+            truncate_str(&self.header(), width, "").into_owned(),
+        )];
+        for position in self.scroll..self.scroll + rows {
+            lines.push(match self.visible.get(position) {
+                Some(&index) => self.row(index, position == self.selected, width)?,
+                None => Zeroizing::new(String::new()),
+            });
+        }
+        lines.push(Zeroizing::new(
+            truncate_str(&self.footer(), width, "").into_owned(),
+        ));
+
+        term.move_cursor_to(0, 0)?;
+        for (number, line) in lines.iter().enumerate() {
+            term.clear_line()?;
+            term.write_str(line)?;
+            if number + 1 < lines.len() {
+                term.write_str("\n")?;
+            }
+        }
+        term.flush()?;
+        Ok(())
+    }
+
+    fn header(&self) -> String {
+        let filter = if self.filter.is_empty() {
+            dim().apply_to("type to filter").to_string()
+        } else {
+            self.filter.clone()
+        };
+        format!(
+            "{} {}  {}",
+            Style::new().for_stderr().bold().apply_to("Filter:"),
+            filter,
+            dim().apply_to(format!(
+                "({} of {} entries)",
+                self.visible.len(),
+                self.entries.len()
+            ))
+        )
+    }
+
+    fn footer(&self) -> String {
+        match &self.status {
+            Some(status) => Style::new()
+                .for_stderr()
+                .yellow()
+                .apply_to(status)
+                .to_string(),
+            None => dim()
+                .apply_to("↑/↓ select  Enter copy  Esc clear filter or quit")
+                .to_string(),
+        }
+    }
+
+    /// Line of the table with the label, the current code and a countdown bar of an entry
+    fn row(&self, index: usize, selected: bool, width: usize) -> Result<Zeroizing<String>> {
+        let entry = &self.entries[index];
+        let label_width = width.saturating_sub(CODE_WIDTH + BAR_WIDTH + 10);
+        let label = pad_str(&label(entry), label_width, Alignment::Left, Some("…")).into_owned();
+        let (marker, label_style) = if selected {
+            ("❯", Style::new().for_stderr().cyan())
+        } else {
+            (" ", Style::new().for_stderr())
+        };
+
+        let (code, code_style, countdown) = match &entry.info {
+            EntryInfo::Hotp(info) => {
+                let counter = dim().apply_to(format!("counter {}", info.counter));
+                match self.hotp_codes.get(&index) {
+                    Some(code) => (
+                        code.clone(),
+                        Style::new().for_stderr().cyan().bold(),
+                        counter.to_string(),
+                    ),
+                    None => (
+                        Zeroizing::new("Enter".to_string()),
+                        dim(),
+                        counter.to_string(),
+                    ),
+                }
+            }
+            EntryInfo::Motp(EntryInfoMotp { pin: None, .. })
+            | EntryInfo::Yandex(EntryInfoYandex { pin: None, .. }) => {
+                (Zeroizing::new("no PIN".to_string()), dim(), String::new())
+            }
+            info => {
+                let remaining_time = calculate_remaining_time(info)?;
+                let period = info.period().unwrap_or(remaining_time).max(1);
+                let filled = (remaining_time as usize * BAR_WIDTH)
+                    .div_ceil(period as usize)
+                    .min(BAR_WIDTH);
+                let style = remaining_time_style(remaining_time).for_stderr();
+                let countdown = style.apply_to(format!(
+                    "{}{} {:>2}s",
+                    "█".repeat(filled),
+                    "░".repeat(BAR_WIDTH - filled),
+                    remaining_time
+                ));
+                (generate_otp(info)?, style.bold(), countdown.to_string())
+            }
+        };
+        Ok(Zeroizing::new(format!(
+            "{} {}  {:<code_width$}  {}",
+            Style::new().for_stderr().green().apply_to(marker),
+            label_style.apply_to(label),
+            code_style.apply_to(code.as_str()),
+            countdown,
+            code_width = CODE_WIDTH
+        )))
+    }
+}
+
+/// How entries are listed, like in the fuzzy selector
+fn label(entry: &Entry) -> String {
+    format!("{} ({})", entry.issuer.trim(), entry.name.trim())
+}
+
+fn dim() -> Style {
+    Style::new().for_stderr().dim()
+}
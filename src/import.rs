@@ -0,0 +1,717 @@
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use base64::{engine::general_purpose, Engine as _};
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha1::Sha1;
+use sha2::Sha256;
+use std::{collections::HashSet, fs, io::ErrorKind, path::Path};
+use zeroize::Zeroizing;
+
+use crate::otp::{
+    encode_secret, normalize_secret, Entry, EntryInfo, EntryInfoHotp, EntryInfoMotp,
+    EntryInfoSteam, EntryInfoTotp, HashAlgorithm,
+};
+use crate::{qr, uri};
+
+/// Export formats of other authenticator apps
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// andOTP JSON backup, plain or encrypted with a password
+    Andotp,
+    /// 2FAS backup, plain or encrypted with a password
+    #[value(name = "2fas")]
+    TwoFas,
+    /// FreeOTP+ JSON backup
+    Freeotp,
+    /// Bitwarden JSON export, plain or password protected
+    Bitwarden,
+    /// Google Authenticator export QR codes as image or as text with one URI per line
+    Google,
+}
+
+/// Read the entries of an export file of another app
+///
+/// The password is only asked for when the file is encrypted.
+pub fn read_entries(
+    format: Format,
+    path: &Path,
+    get_password: impl FnOnce() -> Result<Zeroizing<String>>,
+) -> Result<Vec<Entry>> {
+    match format {
+        Format::Andotp => read_andotp(&Zeroizing::new(fs::read(path)?), get_password),
+        Format::TwoFas => read_2fas(&Zeroizing::new(fs::read_to_string(path)?), get_password),
+        Format::Freeotp => read_freeotp(&Zeroizing::new(fs::read_to_string(path)?)),
+        Format::Bitwarden => {
+            read_bitwarden(&Zeroizing::new(fs::read_to_string(path)?), get_password)
+        }
+        Format::Google => read_google(path),
+    }
+}
+
+/// Split the imported entries into new ones and duplicates, which have the same secret and issuer
+/// as an existing entry or an earlier imported one
+pub fn dedup(entries: Vec<Entry>, existing: &[Entry]) -> (Vec<Entry>, Vec<Entry>) {
+    let key = |entry: &Entry| {
+        let secret = entry.info.secret();
+        (
+            normalize_secret(secret).unwrap_or_else(|_| secret.to_string()),
+            entry.issuer.trim().to_lowercase(),
+        )
+    };
+    let mut seen: HashSet<_> = existing.iter().map(key).collect();
+    entries
+        .into_iter()
+        .partition(|entry| seen.insert(key(entry)))
+}
+
+/// Fields of an entry as the other apps export them
+struct Record {
+    entry_type: String,
+    secret: String,
+    algo: Option<String>,
+    digits: Option<i32>,
+    period: Option<i32>,
+    counter: Option<u64>,
+    issuer: String,
+    name: String,
+    note: String,
+}
+
+impl Record {
+    fn into_entry(self) -> Result<Entry> {
+        let describe = |e| eyre!("Invalid entry {} ({}): {}", self.issuer, self.name, e);
+        let secret = normalize_secret(&self.secret).map_err(describe)?;
+        let algo = match &self.algo {
+            Some(algo) => algo.parse().map_err(describe)?,
+            None => HashAlgorithm::Sha1,
+        };
+        let digits = self.digits.unwrap_or(6);
+        let period = self.period.unwrap_or(30);
+        let info = match self.entry_type.to_uppercase().as_str() {
+            "TOTP" => EntryInfo::Totp(EntryInfoTotp {
+                secret,
+                algo,
+                digits,
+                period,
+            }),
+            "HOTP" => EntryInfo::Hotp(EntryInfoHotp {
+                secret,
+                algo,
+                digits,
+                counter: self.counter.unwrap_or(0),
+            }),
+            "STEAM" => EntryInfo::Steam(EntryInfoSteam {
+                secret,
+                algo: HashAlgorithm::Sha1,
+                digits: 5,
+                period: 30,
+            }),
+            "MOTP" => EntryInfo::Motp(EntryInfoMotp {
+                secret,
+                algo: HashAlgorithm::Md5,
+                digits: 6,
+                period: 10,
+                pin: None,
+            }),
+            other => return Err(describe(eyre!("Unsupported type {}", other))),
+        };
+        info.validate().map_err(describe)?;
+        let mut entry = Entry::new(info, self.issuer.trim(), self.name.trim());
+        entry.note = self.note;
+        Ok(entry)
+    }
+}
+
+/// Report a record that can't be imported, so that it is skipped instead of failing the import
+fn skip_invalid(entry: Result<Entry>) -> Option<Entry> {
+    entry
+        .inspect_err(|e| eprintln!("Skipping entry: {}", e))
+        .ok()
+}
+
+/// Entry of an andOTP backup
+#[derive(Deserialize)]
+struct AndOtpEntry {
+    secret: String,
+    #[serde(default)]
+    issuer: String,
+    #[serde(default)]
+    label: String,
+    #[serde(rename = "type")]
+    entry_type: String,
+    algorithm: Option<String>,
+    digits: Option<i32>,
+    period: Option<i32>,
+    counter: Option<u64>,
+}
+
+/// Read an andOTP backup, a JSON array of entries or the encrypted form of it
+fn read_andotp(
+    data: &[u8],
+    get_password: impl FnOnce() -> Result<Zeroizing<String>>,
+) -> Result<Vec<Entry>> {
+    let json = if data.trim_ascii_start().starts_with(b"[") {
+        Zeroizing::new(data.to_vec())
+    } else {
+        decrypt_andotp(data, &get_password()?)?
+    };
+    let entries = serde_json::from_slice::<Vec<AndOtpEntry>>(&json)?
+        .into_iter()
+        .map(|entry| {
+            // andOTP keeps the secrets of mOTP entries hex encoded
+            let secret = if entry.entry_type.eq_ignore_ascii_case("MOTP") {
+                let secret = Zeroizing::new(hex::decode(&entry.secret).map_err(|e| {
+                    eyre!("Invalid entry {} ({}): {}", entry.issuer, entry.label, e)
+                })?);
+                encode_secret(&secret)
+            } else {
+                entry.secret
+            };
+            Record {
+                entry_type: entry.entry_type,
+                secret,
+                algo: entry.algorithm,
+                digits: entry.digits,
+                period: entry.period,
+                counter: entry.counter,
+                issuer: entry.issuer,
+                name: entry.label,
+                note: String::new(),
+            }
+            .into_entry()
+        })
+        .filter_map(skip_invalid)
+        .collect();
+    Ok(entries)
+}
+
+/// Decrypt an andOTP backup: PBKDF2 iterations, salt and nonce followed by the AES-GCM cipher text
+fn decrypt_andotp(data: &[u8], password: &str) -> Result<Zeroizing<Vec<u8>>> {
+    const SALT_LEN: usize = 12;
+    const NONCE_LEN: usize = 12;
+    if data.len() < 4 + SALT_LEN + NONCE_LEN {
+        return Err(eyre!("Truncated andOTP backup"));
+    }
+    let (iterations, rest) = data.split_at(4);
+    let (salt, rest) = rest.split_at(SALT_LEN);
+    let (nonce, cipher_text) = rest.split_at(NONCE_LEN);
+    let iterations = u32::from_be_bytes(iterations.try_into()?);
+    let key = Zeroizing::new(pbkdf2::pbkdf2_hmac_array::<Sha1, 32>(
+        password.as_bytes(),
+        salt,
+        iterations,
+    ));
+    decrypt_gcm(key.as_slice(), nonce, cipher_text)
+}
+
+/// 2FAS backup
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TwoFasBackup {
+    #[serde(default)]
+    services: Vec<TwoFasService>,
+    services_encrypted: Option<String>,
+}
+
+/// Entry of a 2FAS backup
+#[derive(Deserialize)]
+struct TwoFasService {
+    name: String,
+    secret: String,
+    otp: TwoFasOtp,
+}
+
+/// OTP parameters of a 2FAS entry
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TwoFasOtp {
+    account: Option<String>,
+    issuer: Option<String>,
+    algorithm: Option<String>,
+    digits: Option<i32>,
+    period: Option<i32>,
+    counter: Option<u64>,
+    token_type: Option<String>,
+}
+
+/// Number of PBKDF2 iterations 2FAS derives the backup key with
+const TWO_FAS_ITERATIONS: u32 = 10_000;
+
+/// Read a 2FAS backup, decrypting the services when they are encrypted
+fn read_2fas(
+    json: &str,
+    get_password: impl FnOnce() -> Result<Zeroizing<String>>,
+) -> Result<Vec<Entry>> {
+    let backup: TwoFasBackup = serde_json::from_str(json)?;
+    let services = match backup.services_encrypted {
+        Some(encrypted) if backup.services.is_empty() => {
+            let parts = encrypted
+                .split(':')
+                .map(|part| general_purpose::STANDARD.decode(part))
+                .collect::<Result<Vec<Vec<u8>>, _>>()?;
+            let [cipher_text, salt, nonce] = parts.as_slice() else {
+                return Err(eyre!("Invalid encrypted 2FAS services"));
+            };
+            let password = get_password()?;
+            let key = Zeroizing::new(pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(
+                password.as_bytes(),
+                salt,
+                TWO_FAS_ITERATIONS,
+            ));
+            serde_json::from_slice(&decrypt_gcm(key.as_slice(), nonce, cipher_text)?)?
+        }
+        _ => backup.services,
+    };
+    let entries = services
+        .into_iter()
+        .map(|service| {
+            Record {
+                entry_type: service.otp.token_type.unwrap_or("TOTP".to_string()),
+                secret: service.secret,
+                algo: service.otp.algorithm,
+                digits: service.otp.digits,
+                period: service.otp.period,
+                counter: service.otp.counter,
+                issuer: service
+                    .otp
+                    .issuer
+                    .filter(|issuer| !issuer.is_empty())
+                    .unwrap_or(service.name),
+                name: service.otp.account.unwrap_or_default(),
+                note: String::new(),
+            }
+            .into_entry()
+        })
+        .filter_map(skip_invalid)
+        .collect();
+    Ok(entries)
+}
+
+/// FreeOTP+ backup
+#[derive(Deserialize)]
+struct FreeOtpBackup {
+    tokens: Vec<FreeOtpToken>,
+}
+
+/// Entry of a FreeOTP+ backup
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct FreeOtpToken {
+    #[serde(rename = "type")]
+    token_type: String,
+    /// Secret as signed bytes of a Java byte array
+    secret: Vec<i8>,
+    algo: Option<String>,
+    digits: Option<i32>,
+    period: Option<i32>,
+    counter: Option<u64>,
+    #[serde(default)]
+    issuer_ext: String,
+    #[serde(default)]
+    label: String,
+}
+
+/// Read a FreeOTP+ JSON backup
+fn read_freeotp(json: &str) -> Result<Vec<Entry>> {
+    let backup: FreeOtpBackup = serde_json::from_str(json)?;
+    let entries = backup
+        .tokens
+        .into_iter()
+        .map(|token| {
+            let secret: Vec<u8> = token.secret.iter().map(|&byte| byte as u8).collect();
+            Record {
+                entry_type: token.token_type,
+                secret: encode_secret(&secret),
+                algo: token.algo,
+                digits: token.digits,
+                period: token.period,
+                counter: token.counter,
+                issuer: token.issuer_ext,
+                name: token.label,
+                note: String::new(),
+            }
+            .into_entry()
+        })
+        .filter_map(skip_invalid)
+        .collect();
+    Ok(entries)
+}
+
+/// Bitwarden JSON export, its items are in `data` when it is encrypted
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BitwardenExport {
+    #[serde(default)]
+    encrypted: bool,
+    #[serde(default)]
+    password_protected: bool,
+    salt: Option<String>,
+    kdf_type: Option<u32>,
+    kdf_iterations: Option<u32>,
+    data: Option<String>,
+    #[serde(default)]
+    items: Vec<BitwardenItem>,
+}
+
+/// Item of a Bitwarden export
+#[derive(Deserialize)]
+struct BitwardenItem {
+    name: String,
+    notes: Option<String>,
+    login: Option<BitwardenLogin>,
+}
+
+/// Login of a Bitwarden item
+#[derive(Deserialize)]
+struct BitwardenLogin {
+    username: Option<String>,
+    totp: Option<String>,
+}
+
+/// Read a Bitwarden JSON export, only the login items with a TOTP are imported
+fn read_bitwarden(
+    json: &str,
+    get_password: impl FnOnce() -> Result<Zeroizing<String>>,
+) -> Result<Vec<Entry>> {
+    let mut export: BitwardenExport = serde_json::from_str(json)?;
+    if export.encrypted {
+        let decrypted = decrypt_bitwarden(&export, &get_password()?)?;
+        export = serde_json::from_slice(&decrypted)?;
+    }
+    let entries = export
+        .items
+        .into_iter()
+        .filter_map(|item| {
+            let login = item.login?;
+            let totp = login.totp.filter(|totp| !totp.is_empty())?;
+            let issuer = item.name;
+            let name = login.username.unwrap_or_default();
+            let note = item.notes.unwrap_or_default();
+            let entry = if totp.starts_with("otpauth://") {
+                uri::parse_otpauth(&totp).map(|mut entry| {
+                    if entry.issuer.is_empty() {
+                        entry.issuer = issuer;
+                    }
+                    if entry.name.is_empty() {
+                        entry.name = name;
+                    }
+                    entry.note = note;
+                    entry
+                })
+            } else {
+                let (entry_type, secret) = match totp.strip_prefix("steam://") {
+                    Some(secret) => ("STEAM", secret),
+                    None => ("TOTP", totp.as_str()),
+                };
+                Record {
+                    entry_type: entry_type.to_string(),
+                    secret: secret.to_string(),
+                    algo: None,
+                    digits: None,
+                    period: None,
+                    counter: None,
+                    issuer,
+                    name,
+                    note,
+                }
+                .into_entry()
+            };
+            Some(entry)
+        })
+        .filter_map(skip_invalid)
+        .collect();
+    Ok(entries)
+}
+
+/// Decrypt the `data` of a password protected Bitwarden export
+///
+/// The key is derived with PBKDF2 and stretched with HKDF into an AES-CBC key and an HMAC key,
+/// `data` has the form `2.<iv>|<cipher text>|<mac>`.
+fn decrypt_bitwarden(export: &BitwardenExport, password: &str) -> Result<Zeroizing<Vec<u8>>> {
+    if !export.password_protected {
+        return Err(eyre!(
+            "Only password protected Bitwarden exports can be decrypted outside of Bitwarden"
+        ));
+    }
+    if export.kdf_type != Some(0) {
+        return Err(eyre!(
+            "Only PBKDF2 protected Bitwarden exports are supported"
+        ));
+    }
+    let (Some(salt), Some(iterations), Some(data)) =
+        (&export.salt, export.kdf_iterations, &export.data)
+    else {
+        return Err(eyre!("Incomplete encrypted Bitwarden export"));
+    };
+    let key = Zeroizing::new(pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(
+        password.as_bytes(),
+        salt.as_bytes(),
+        iterations,
+    ));
+    let hkdf = Hkdf::<Sha256>::from_prk(key.as_slice()).map_err(|_| eyre!("Invalid key length"))?;
+    let mut enc_key = Zeroizing::new([0u8; 32]);
+    let mut mac_key = Zeroizing::new([0u8; 32]);
+    hkdf.expand(b"enc", enc_key.as_mut_slice())
+        .and_then(|_| hkdf.expand(b"mac", mac_key.as_mut_slice()))
+        .map_err(|_| eyre!("Failed to stretch key"))?;
+
+    let parts = data
+        .strip_prefix("2.")
+        .ok_or(eyre!("Unsupported Bitwarden encryption type"))?
+        .split('|')
+        .map(|part| general_purpose::STANDARD.decode(part))
+        .collect::<Result<Vec<Vec<u8>>, _>>()?;
+    let [iv, cipher_text, mac] = parts.as_slice() else {
+        return Err(eyre!("Invalid encrypted Bitwarden data"));
+    };
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(mac_key.as_slice())
+        .map_err(|_| eyre!("Invalid key length"))?;
+    hmac.update(iv);
+    hmac.update(cipher_text);
+    hmac.verify_slice(mac)
+        .map_err(|_| eyre!("Failed to decrypt, wrong password?"))?;
+    cbc::Decryptor::<aes::Aes256>::new_from_slices(enc_key.as_slice(), iv)
+        .map_err(|_| eyre!("Invalid IV length"))?
+        .decrypt_padded_vec_mut::<Pkcs7>(cipher_text)
+        .map(Zeroizing::new)
+        .map_err(|_| eyre!("Failed to decrypt"))
+}
+
+/// Read Google Authenticator export QR codes from an image, or their URIs from a text file
+fn read_google(path: &Path) -> Result<Vec<Entry>> {
+    let contents = match fs::read_to_string(path) {
+        Ok(text) => text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect(),
+        Err(e) if e.kind() == ErrorKind::InvalidData => qr::decode_image(path)?,
+        Err(e) => return Err(e.into()),
+    };
+    Ok(contents
+        .iter()
+        .map(|content| uri::parse_qr_content(content))
+        .collect::<Result<Vec<Vec<Entry>>>>()?
+        .concat())
+}
+
+/// Decrypt AES-GCM cipher text with the tag appended
+fn decrypt_gcm(key: &[u8], nonce: &[u8], cipher_text: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    if nonce.len() != 12 {
+        return Err(eyre!("Invalid nonce length"));
+    }
+    Aes256Gcm::new_from_slice(key)
+        .map_err(|_| eyre!("Invalid key length"))?
+        .decrypt(Nonce::from_slice(nonce), cipher_text)
+        .map(Zeroizing::new)
+        .map_err(|_| eyre!("Failed to decrypt, wrong password?"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("res")
+            .join("import")
+            .join(name)
+    }
+
+    fn read_plain(format: Format, name: &str) -> Vec<Entry> {
+        read_entries(format, &fixture(name), || {
+            panic!("Password asked for the plain file {}", name)
+        })
+        .unwrap()
+    }
+
+    fn read_encrypted(format: Format, name: &str, password: &str) -> Result<Vec<Entry>> {
+        read_entries(format, &fixture(name), || {
+            Ok(Zeroizing::new(password.to_string()))
+        })
+    }
+
+    fn summary(entries: &[Entry]) -> Vec<(&str, &str, &EntryInfo)> {
+        entries
+            .iter()
+            .map(|entry| (entry.issuer.as_str(), entry.name.as_str(), &entry.info))
+            .collect()
+    }
+
+    fn totp(secret: &str, algo: HashAlgorithm, digits: i32, period: i32) -> EntryInfo {
+        EntryInfo::Totp(EntryInfoTotp {
+            secret: secret.to_string(),
+            algo,
+            digits,
+            period,
+        })
+    }
+
+    fn hotp(secret: &str, digits: i32, counter: u64) -> EntryInfo {
+        EntryInfo::Hotp(EntryInfoHotp {
+            secret: secret.to_string(),
+            algo: HashAlgorithm::Sha1,
+            digits,
+            counter,
+        })
+    }
+
+    fn steam(secret: &str) -> EntryInfo {
+        EntryInfo::Steam(EntryInfoSteam {
+            secret: secret.to_string(),
+            algo: HashAlgorithm::Sha1,
+            digits: 5,
+            period: 30,
+        })
+    }
+
+    fn andotp_entries() -> Vec<(&'static str, &'static str, EntryInfo)> {
+        vec![
+            (
+                "GitHub",
+                "dave",
+                totp("JBSWY3DPEHPK3PXP", HashAlgorithm::Sha1, 6, 30),
+            ),
+            ("Example", "hotpuser", hotp("GEZDGNBVGY3TQOJQ", 6, 5)),
+            ("Steam", "gamer", steam("JBSWY3DPEHPK3PXQ")),
+            (
+                "Mobile",
+                "mo",
+                EntryInfo::Motp(EntryInfoMotp {
+                    secret: "4MKSV7XGEWM4Q".to_string(),
+                    algo: HashAlgorithm::Md5,
+                    digits: 6,
+                    period: 10,
+                    pin: None,
+                }),
+            ),
+        ]
+    }
+
+    fn twofas_entries() -> Vec<(&'static str, &'static str, EntryInfo)> {
+        vec![
+            (
+                "Mastodon",
+                "alice",
+                totp("KRSXG5CTMVRXEZLU", HashAlgorithm::Sha256, 6, 30),
+            ),
+            (
+                "GitHub",
+                "dave",
+                totp("JBSWY3DPEHPK3PXP", HashAlgorithm::Sha1, 6, 30),
+            ),
+            ("Counter", "carl", hotp("GEZDGNBVGY3TQOJQ", 8, 3)),
+        ]
+    }
+
+    fn bitwarden_entries() -> Vec<(&'static str, &'static str, EntryInfo)> {
+        vec![
+            (
+                "Proton",
+                "carol",
+                totp("ONSWG4TFOQYTEMZU", HashAlgorithm::Sha1, 8, 30),
+            ),
+            ("Valve", "gamer2", steam("KRUGKIDROVUWG2ZA")),
+            (
+                "Plain",
+                "pat",
+                totp("JBSWY3DPEHPK3PXR", HashAlgorithm::Sha1, 6, 30),
+            ),
+        ]
+    }
+
+    fn assert_entries(entries: &[Entry], expected: &[(&str, &str, EntryInfo)]) {
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(issuer, name, info)| (*issuer, *name, info))
+            .collect();
+        assert_eq!(summary(entries), expected);
+    }
+
+    #[test]
+    fn andotp_skips_unsupported_entries() {
+        // The last entry of the backup has an unknown algorithm
+        let entries = read_plain(Format::Andotp, "andotp.json");
+        assert_entries(&entries, &andotp_entries());
+    }
+
+    #[test]
+    fn andotp_encrypted() {
+        let entries = read_encrypted(Format::Andotp, "andotp.json.aes", "test").unwrap();
+        assert_entries(&entries, &andotp_entries());
+        assert!(read_encrypted(Format::Andotp, "andotp.json.aes", "wrong").is_err());
+    }
+
+    #[test]
+    fn twofas() {
+        let entries = read_plain(Format::TwoFas, "plain.2fas");
+        assert_entries(&entries, &twofas_entries());
+    }
+
+    #[test]
+    fn twofas_encrypted() {
+        let entries = read_encrypted(Format::TwoFas, "enc.2fas", "test").unwrap();
+        assert_entries(&entries, &twofas_entries());
+        assert!(read_encrypted(Format::TwoFas, "enc.2fas", "wrong").is_err());
+    }
+
+    #[test]
+    fn freeotp() {
+        // The secret is a Java byte array with signed bytes
+        let entries = read_plain(Format::Freeotp, "freeotp.json");
+        assert_entries(
+            &entries,
+            &[(
+                "FreeCorp",
+                "bob",
+                totp("MFRGGZDFMZTWQ2MW", HashAlgorithm::Sha512, 8, 60),
+            )],
+        );
+    }
+
+    #[test]
+    fn bitwarden() {
+        // Items without a TOTP are left out, the one with an invalid secret is skipped
+        let entries = read_plain(Format::Bitwarden, "bw.json");
+        assert_entries(&entries, &bitwarden_entries());
+        assert_eq!(entries[0].note, "work account");
+    }
+
+    #[test]
+    fn bitwarden_encrypted() {
+        let entries = read_encrypted(Format::Bitwarden, "bw_enc.json", "test").unwrap();
+        assert_entries(&entries, &bitwarden_entries());
+        assert!(read_encrypted(Format::Bitwarden, "bw_enc.json", "wrong").is_err());
+    }
+
+    #[test]
+    fn google() {
+        let entries = read_plain(Format::Google, "google.txt");
+        assert_entries(
+            &entries,
+            &[
+                (
+                    "Google",
+                    "erin",
+                    totp("GEZDGNBVGY3TQOJQ", HashAlgorithm::Sha1, 6, 30),
+                ),
+                ("Acme", "frank", hotp("JBSWY3DPEHPK3PXP", 6, 2)),
+            ],
+        );
+    }
+
+    #[test]
+    fn dedup_by_secret_and_issuer() {
+        let existing = read_plain(Format::TwoFas, "plain.2fas");
+        let (new, duplicates) = dedup(read_plain(Format::Andotp, "andotp.json"), &existing);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].issuer, "GitHub");
+        assert_eq!(new.len(), 3);
+    }
+}
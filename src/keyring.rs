@@ -0,0 +1,319 @@
+use color_eyre::eyre::{eyre, Result};
+use secret_service::{blocking::SecretService, EncryptionType};
+use std::{collections::HashMap, fs, path::Path};
+use zeroize::Zeroizing;
+
+/// Value of the `application` attribute of the stored passwords
+const APPLICATION: &str = "aegis-cli";
+
+/// Connect to the Secret Service, with an encrypted session when the service supports it
+fn connect() -> Result<SecretService<'static>> {
+    SecretService::connect(EncryptionType::Dh)
+        .or_else(|_| SecretService::connect(EncryptionType::Plain))
+        .map_err(|e| eyre!("Failed to connect to the Secret Service: {}", e))
+}
+
+/// Canonical path of the vault file, which identifies its password in the keyring
+fn vault_id(vault_file: &Path) -> Result<String> {
+    Ok(fs::canonicalize(vault_file)?.display().to_string())
+}
+
+/// Attributes of the password of a vault
+fn attributes(vault_id: &str) -> HashMap<&str, &str> {
+    HashMap::from([("application", APPLICATION), ("vault", vault_id)])
+}
+
+/// Password of the vault stored in the keyring, unlocking the keyring when needed
+pub fn lookup_password(vault_file: &Path) -> Result<Option<Zeroizing<String>>> {
+    let vault_id = vault_id(vault_file)?;
+    let service = connect()?;
+    let items = service.search_items(attributes(&vault_id))?;
+    let Some(item) = items.unlocked.first().or(items.locked.first()) else {
+        return Ok(None);
+    };
+    item.unlock()?;
+    let secret = Zeroizing::new(item.get_secret()?);
+    Ok(Some(Zeroizing::new(String::from_utf8(secret.to_vec())?)))
+}
+
+/// Store the password of the vault in the default collection of the keyring, replacing an
+/// earlier one
+pub fn store_password(vault_file: &Path, password: &str) -> Result<()> {
+    let vault_id = vault_id(vault_file)?;
+    let service = connect()?;
+    let collection = service.get_default_collection()?;
+    collection.unlock()?;
+    collection.create_item(
+        &format!("Aegis vault password for {}", vault_id),
+        attributes(&vault_id),
+        password.as_bytes(),
+        true,
+        "text/plain",
+    )?;
+    Ok(())
+}
+
+/// Remove the stored password of the vault, returning whether there was one
+pub fn forget_password(vault_file: &Path) -> Result<bool> {
+    let vault_id = vault_id(vault_file)?;
+    let service = connect()?;
+    let items = service.search_items(attributes(&vault_id))?;
+    let mut found = false;
+    for item in items.unlocked.iter().chain(items.locked.iter()) {
+        item.delete()?;
+        found = true;
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        env,
+        io::{BufRead, BufReader},
+        process::{self, Child, Command, Stdio},
+        sync::{Arc, Mutex},
+    };
+    use zbus::{
+        blocking::{connection, Connection},
+        fdo, interface,
+        object_server::ObjectServer,
+        zvariant::{ObjectPath, OwnedObjectPath, OwnedValue, Value},
+    };
+
+    /// Secret as the Secret Service API transfers it: session, parameters, value and content type
+    type Secret = (OwnedObjectPath, Vec<u8>, Vec<u8>, String);
+
+    /// Items of the stand-in service by object path: attributes, secret and label
+    type Items = Arc<Mutex<HashMap<String, (HashMap<String, String>, Vec<u8>, String)>>>;
+
+    const COLLECTION: &str = "/org/freedesktop/secrets/collection/login";
+
+    fn object_path(path: &str) -> OwnedObjectPath {
+        ObjectPath::try_from(path.to_string()).unwrap().into()
+    }
+
+    fn search(items: &Items, attributes: &HashMap<String, String>) -> Vec<OwnedObjectPath> {
+        items
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, (item_attributes, _, _))| {
+                attributes
+                    .iter()
+                    .all(|(key, value)| item_attributes.get(key) == Some(value))
+            })
+            .map(|(path, _)| object_path(path))
+            .collect()
+    }
+
+    /// Stand-in for the Secret Service with a single, always unlocked collection and only plain
+    /// sessions
+    struct StandInService(Items);
+
+    #[interface(name = "org.freedesktop.Secret.Service")]
+    impl StandInService {
+        fn open_session(
+            &self,
+            algorithm: String,
+            _input: Value<'_>,
+        ) -> fdo::Result<(OwnedValue, OwnedObjectPath)> {
+            if algorithm != "plain" {
+                return Err(fdo::Error::NotSupported(algorithm));
+            }
+            Ok((
+                OwnedValue::try_from(Value::from("")).unwrap(),
+                object_path("/org/freedesktop/secrets/session/1"),
+            ))
+        }
+
+        fn search_items(
+            &self,
+            attributes: HashMap<String, String>,
+        ) -> (Vec<OwnedObjectPath>, Vec<OwnedObjectPath>) {
+            (search(&self.0, &attributes), vec![])
+        }
+
+        fn unlock(&self, objects: Vec<OwnedObjectPath>) -> (Vec<OwnedObjectPath>, OwnedObjectPath) {
+            (objects, object_path("/"))
+        }
+
+        fn read_alias(&self, _name: String) -> OwnedObjectPath {
+            object_path(COLLECTION)
+        }
+
+        #[zbus(property)]
+        fn collections(&self) -> Vec<OwnedObjectPath> {
+            vec![object_path(COLLECTION)]
+        }
+    }
+
+    struct StandInCollection(Items);
+
+    #[interface(name = "org.freedesktop.Secret.Collection")]
+    impl StandInCollection {
+        async fn create_item(
+            &self,
+            properties: HashMap<String, OwnedValue>,
+            secret: Secret,
+            replace: bool,
+            #[zbus(object_server)] server: &ObjectServer,
+        ) -> fdo::Result<(OwnedObjectPath, OwnedObjectPath)> {
+            let property = |name: &str| {
+                properties
+                    .get(&format!("org.freedesktop.Secret.Item.{}", name))
+                    .map(|value| value.try_clone().unwrap())
+            };
+            let attributes: HashMap<String, String> = property("Attributes")
+                .map(|value| value.try_into().unwrap())
+                .unwrap_or_default();
+            let label: String = property("Label")
+                .map(|value| value.try_into().unwrap())
+                .unwrap_or_default();
+            if replace {
+                for path in search(&self.0, &attributes) {
+                    self.0.lock().unwrap().remove(path.as_str());
+                }
+            }
+            let path = {
+                let mut items = self.0.lock().unwrap();
+                let path = format!("{}/{}", COLLECTION, items.len() + 1);
+                items.insert(path.clone(), (attributes, secret.2, label));
+                path
+            };
+            server
+                .at(path.as_str(), StandInItem(self.0.clone(), path.clone()))
+                .await?;
+            Ok((object_path(&path), object_path("/")))
+        }
+
+        fn search_items(&self, attributes: HashMap<String, String>) -> Vec<OwnedObjectPath> {
+            search(&self.0, &attributes)
+        }
+
+        #[zbus(property)]
+        fn locked(&self) -> bool {
+            false
+        }
+
+        #[zbus(property)]
+        fn label(&self) -> String {
+            "Login".to_string()
+        }
+    }
+
+    struct StandInItem(Items, String);
+
+    #[interface(name = "org.freedesktop.Secret.Item")]
+    impl StandInItem {
+        fn get_secret(&self, session: OwnedObjectPath) -> fdo::Result<Secret> {
+            let items = self.0.lock().unwrap();
+            let (_, secret, _) = items
+                .get(&self.1)
+                .ok_or(fdo::Error::UnknownObject(self.1.clone()))?;
+            Ok((session, vec![], secret.clone(), "text/plain".to_string()))
+        }
+
+        fn delete(&self) -> OwnedObjectPath {
+            self.0.lock().unwrap().remove(&self.1);
+            object_path("/")
+        }
+
+        #[zbus(property)]
+        fn locked(&self) -> bool {
+            false
+        }
+
+        #[zbus(property)]
+        fn attributes(&self) -> HashMap<String, String> {
+            let items = self.0.lock().unwrap();
+            items
+                .get(&self.1)
+                .map(|item| item.0.clone())
+                .unwrap_or_default()
+        }
+
+        #[zbus(property)]
+        fn label(&self) -> String {
+            let items = self.0.lock().unwrap();
+            items
+                .get(&self.1)
+                .map(|item| item.2.clone())
+                .unwrap_or_default()
+        }
+    }
+
+    /// Private session bus, stopped when dropped
+    struct Bus(Child);
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// Start a session bus with the stand-in service on it, `None` when there is no D-Bus daemon
+    fn start_stand_in() -> Option<(Bus, Connection)> {
+        let mut daemon = Command::new("dbus-daemon")
+            .args(["--session", "--nofork", "--print-address"])
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        let stdout = daemon.stdout.take()?;
+        let bus = Bus(daemon);
+        let mut address = String::new();
+        BufReader::new(stdout).read_line(&mut address).ok()?;
+        let address = address.trim();
+        if address.is_empty() {
+            return None;
+        }
+        let items = Items::default();
+        let connection = connection::Builder::address(address)
+            .ok()?
+            .name("org.freedesktop.secrets")
+            .ok()?
+            .serve_at("/org/freedesktop/secrets", StandInService(items.clone()))
+            .ok()?
+            .serve_at(COLLECTION, StandInCollection(items))
+            .ok()?
+            .build()
+            .ok()?;
+        // The functions under test connect to the session bus of the environment
+        env::set_var("DBUS_SESSION_BUS_ADDRESS", address);
+        Some((bus, connection))
+    }
+
+    #[test]
+    fn store_lookup_and_forget() {
+        let Some((_bus, _service)) = start_stand_in() else {
+            eprintln!("Skipping, no D-Bus daemon to run a stand-in Secret Service on");
+            return;
+        };
+        let vault = env::temp_dir().join(format!("aegis-keyring-{}.json", process::id()));
+        let other_vault = env::temp_dir().join(format!("aegis-keyring-{}-2.json", process::id()));
+        fs::write(&vault, "{}").unwrap();
+        fs::write(&other_vault, "{}").unwrap();
+        let lookup = |vault_file: &Path| {
+            lookup_password(vault_file)
+                .unwrap()
+                .map(|password| password.to_string())
+        };
+
+        assert_eq!(lookup(&vault), None);
+        store_password(&vault, "first").unwrap();
+        store_password(&vault, "second").unwrap();
+        store_password(&other_vault, "other").unwrap();
+        assert_eq!(lookup(&vault).as_deref(), Some("second"));
+        assert_eq!(lookup(&other_vault).as_deref(), Some("other"));
+
+        assert!(forget_password(&vault).unwrap());
+        assert!(!forget_password(&vault).unwrap());
+        assert_eq!(lookup(&vault), None);
+        assert_eq!(lookup(&other_vault).as_deref(), Some("other"));
+        fs::remove_file(&vault).unwrap();
+        fs::remove_file(&other_vault).unwrap();
+    }
+}
@@ -1,31 +1,242 @@
-use clap::{crate_version, Args, Parser};
+use clap::{crate_version, ArgGroup, Args, Parser, Subcommand, ValueEnum};
 use color_eyre::eyre::{eyre, Result};
 use color_eyre::owo_colors::OwoColorize;
 use console::{Key, Style, Term};
-use dialoguer::{theme::ColorfulTheme, FuzzySelect, Password};
-use std::sync::mpsc::{self, TryRecvError};
-use std::{env, fs, path::PathBuf, process::exit, thread, time::Duration};
+use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect, Password};
+use regex::{Regex, RegexBuilder};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    convert::Infallible,
+    env,
+    fmt::Write as _,
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process::{self, exit},
+    slice, thread,
+    time::{Duration, Instant},
+};
 
-use aegis_vault_utils::{
-    otp::{calculate_remaining_time, generate_otp, Entry, EntryInfo},
-    vault::{parse_vault, PasswordGetter},
+use clipboard::CodeClipboard;
+use otp::{
+    calculate_remaining_time, calculate_remaining_time_at, generate_otp, generate_otp_at,
+    normalize_secret, time_since_epoch, Entry, EntryInfo, EntryInfoHotp, EntryInfoMotp,
+    EntryInfoSteam, EntryInfoTotp, EntryInfoYandex, HashAlgorithm,
 };
+use usage::Usage;
+use vault::{PasswordGetter, ScryptParams, Vault};
+use zeroize::Zeroizing;
+
+#[cfg(unix)]
+mod agent;
+mod clipboard;
+mod dashboard;
+mod import;
+#[cfg(target_os = "linux")]
+mod keyring;
+mod otp;
+mod qr;
+mod uri;
+mod usage;
+mod vault;
+
+/// Time the password command gets to print the password, including entering a GPG passphrase
+const PASSWORD_COMMAND_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Seconds before the code changes from which the next code is shown as well
+const NEXT_CODE_TIME: i32 = 5;
+
+/// Range of log2(N) tried by kdf-bench
+const MIN_BENCH_LOG_N: u32 = 10;
+const MAX_BENCH_LOG_N: u32 = 22;
 
 #[derive(Parser)]
 #[clap(
     name = "aegis-cli",
     about = format!("{}{} - {}", "aegis-cli v".bold().underline(), crate_version!().bold().underline(), "Show TOTPs from Aegis vault on CLI".bold()),
-    version = crate_version!()
+    version = crate_version!(),
+    subcommand_negates_reqs = true
 )]
 struct Cli {
-    #[clap(help = "Path to Aegis vault file", env = "AEGIS_VAULT_FILE")]
-    vault_file: PathBuf,
+    #[clap(
+        required = true,
+        help = "Path to Aegis vault file",
+        env = "AEGIS_VAULT_FILE"
+    )]
+    vault_file: Option<PathBuf>,
     #[clap(flatten)]
     password_input: PasswordInput,
     #[clap(flatten, help = "Filter by ISSUER")]
     entry_filter: EntryFilter,
     #[clap(short, long, help = "Display (pre-filtered) entries in JSON on stdout")]
     json: bool,
+    #[clap(
+        long,
+        value_enum,
+        value_name = "ORDER",
+        env = "AEGIS_SORT",
+        help = "ORDER of the listed entries, favorites always come first [default: recent in the \
+                selector and the dashboard, the order of the vault in other output]"
+    )]
+    sort: Option<SortOrder>,
+    #[clap(
+        long,
+        env = "AEGIS_USAGE_FILE",
+        help = "Path to the file recording how often and how recently entries were selected"
+    )]
+    usage_file: Option<PathBuf>,
+    #[clap(
+        long,
+        conflicts_with = "usage_file",
+        help = "Neither use nor record which entries were selected"
+    )]
+    no_usage: bool,
+    #[clap(flatten)]
+    validity: Validity,
+    #[clap(flatten)]
+    clipboard: ClipboardOptions,
+    #[cfg(unix)]
+    #[clap(
+        long,
+        env = "AEGIS_AGENT_SOCKET",
+        help = "Path to the socket of the unlock agent"
+    )]
+    agent_socket: Option<PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[clap(about = "Print only the code of the single (pre-filtered) entry matching QUERY")]
+    Code {
+        #[clap(help = "Match ISSUER or NAME of the entry")]
+        query: Option<String>,
+    },
+    #[clap(about = "Add a new entry to the vault")]
+    Add(NewEntry),
+    #[clap(about = "Change the single (pre-filtered) entry matching QUERY")]
+    #[clap(group(ArgGroup::new("changes").required(true).multiple(true)))]
+    Edit {
+        #[clap(help = "Match ISSUER or NAME of the entry")]
+        query: Option<String>,
+        #[clap(long, group = "changes", help = "New ISSUER of the entry")]
+        issuer: Option<String>,
+        #[clap(long, group = "changes", help = "New NAME of the entry")]
+        name: Option<String>,
+        #[clap(long, group = "changes", help = "New NOTE of the entry")]
+        note: Option<String>,
+    },
+    #[clap(about = "Remove the single (pre-filtered) entry matching QUERY from the vault")]
+    Rm {
+        #[clap(help = "Match ISSUER or NAME of the entry")]
+        query: Option<String>,
+        #[clap(short, long, help = "Don't ask for confirmation")]
+        yes: bool,
+    },
+    #[clap(about = "Import the entries of another app's export FILE, skipping duplicates")]
+    Import {
+        #[clap(long, value_enum, help = "FORMAT of the export file")]
+        from: import::Format,
+        #[clap(help = "Export FILE of the other app")]
+        file: PathBuf,
+    },
+    #[clap(about = "Print the (pre-filtered) entries as otpauth:// URIs, including their secrets")]
+    Export {
+        #[clap(long, help = "Also render each URI as a QR code in the terminal")]
+        qr: bool,
+        #[clap(
+            long,
+            requires = "out",
+            conflicts_with = "qr",
+            help = "Write the whole vault unencrypted to the --out FILE instead"
+        )]
+        plain: bool,
+        #[clap(
+            short,
+            long,
+            value_name = "FILE",
+            requires = "plain",
+            help = "New FILE for the plain text vault"
+        )]
+        out: Option<PathBuf>,
+        #[clap(short, long, help = "Don't ask for confirmation")]
+        yes: bool,
+    },
+    #[clap(about = "Write the vault encrypted with a new master key and password to a new FILE")]
+    Encrypt {
+        #[clap(
+            short,
+            long,
+            value_name = "FILE",
+            help = "New FILE for the encrypted vault"
+        )]
+        out: PathBuf,
+        #[clap(flatten)]
+        new_password: NewPassword,
+    },
+    #[clap(about = "Change the password of the slot the vault was unlocked with")]
+    Passwd {
+        #[clap(flatten)]
+        new_password: NewPassword,
+    },
+    #[clap(about = "List, add or remove the master key slots of the vault")]
+    Slot {
+        #[clap(subcommand)]
+        command: SlotCommand,
+    },
+    #[clap(about = "Find the scrypt cost N for new password slots that takes about MILLISECONDS")]
+    KdfBench {
+        #[clap(
+            long,
+            value_name = "MILLISECONDS",
+            default_value_t = 1000,
+            help = "Target unlock time in MILLISECONDS"
+        )]
+        target: u64,
+        #[clap(long, default_value_t = ScryptParams::default().r, help = "scrypt block size r")]
+        scrypt_r: u32,
+        #[clap(long, default_value_t = ScryptParams::default().p, help = "scrypt parallelization p")]
+        scrypt_p: u32,
+    },
+    #[clap(about = "Show the live codes of all (pre-filtered) entries in a full-screen table")]
+    Dashboard,
+    #[cfg(unix)]
+    #[clap(about = "Keep the vault unlocked for other invocations until idle for SECONDS")]
+    Agent {
+        #[clap(
+            long,
+            value_name = "SECONDS",
+            default_value_t = 900,
+            help = "Exit after SECONDS without requests"
+        )]
+        idle_timeout: u64,
+    },
+}
+
+#[derive(Subcommand)]
+enum SlotCommand {
+    #[clap(about = "List the slots of the vault")]
+    List,
+    #[clap(about = "Add a slot for another password, the database stays encrypted as it is")]
+    Add {
+        #[clap(flatten)]
+        new_password: NewPassword,
+    },
+    #[clap(about = "Create a new key FILE with a random key and add a slot for it")]
+    AddKeyFile {
+        #[clap(help = "Path of the new key FILE")]
+        file: PathBuf,
+    },
+    #[clap(about = "Remove the slot with UUID")]
+    Rm {
+        #[clap(help = "UUID of the slot")]
+        uuid: String,
+        #[clap(short, long, help = "Don't ask for confirmation")]
+        yes: bool,
+    },
 }
 
 #[derive(Args)]
@@ -43,32 +254,505 @@ struct PasswordInput {
         env = "AEGIS_PASSWORD",
         help = "PASSWORD to unlock Aegis vault",
         conflicts_with = "password_file",
-        hide_env_values = true
+        hide_env_values = true,
+        value_parser = parse_secret
+    )]
+    password: Option<Zeroizing<String>>,
+    #[clap(
+        long,
+        env = "AEGIS_PASSWORD_COMMAND",
+        help = "COMMAND printing the Aegis vault password on the first line of its output",
+        conflicts_with_all = ["password_file", "password"]
+    )]
+    password_command: Option<String>,
+    #[clap(
+        short,
+        long,
+        env = "AEGIS_KEY_FILE",
+        help = "Path to key file to unlock Aegis vault instead of a password",
+        conflicts_with_all = ["password_file", "password", "password_command"]
+    )]
+    key_file: Option<PathBuf>,
+    #[cfg(target_os = "linux")]
+    #[clap(
+        long,
+        help = "Remember the password in the Secret Service (GNOME Keyring, KWallet) for --keyring"
+    )]
+    remember: bool,
+    #[cfg(target_os = "linux")]
+    #[clap(
+        long,
+        env = "AEGIS_KEYRING",
+        value_parser = clap::builder::FalseyValueParser::new(),
+        help = "Unlock with the password remembered in the Secret Service, if there is one"
+    )]
+    keyring: bool,
+    #[cfg(target_os = "linux")]
+    #[clap(
+        long,
+        conflicts_with = "remember",
+        help = "Remove the remembered password of the vault from the Secret Service and exit"
+    )]
+    forget: bool,
+}
+
+#[derive(Args)]
+struct NewPassword {
+    #[clap(
+        long,
+        env = "AEGIS_NEW_PASSWORD_FILE",
+        help = "Path to file with the new vault password, asked for when not given"
+    )]
+    new_password_file: Option<PathBuf>,
+    #[clap(
+        long,
+        default_value_t = ScryptParams::default().n,
+        help = "scrypt cost N of the new password slot, a power of two (see kdf-bench)"
     )]
-    password: Option<String>,
+    scrypt_n: u32,
+    #[clap(long, default_value_t = ScryptParams::default().r, help = "scrypt block size r of the new password slot")]
+    scrypt_r: u32,
+    #[clap(long, default_value_t = ScryptParams::default().p, help = "scrypt parallelization p of the new password slot")]
+    scrypt_p: u32,
+}
+
+impl NewPassword {
+    fn scrypt_params(&self) -> ScryptParams {
+        ScryptParams {
+            n: self.scrypt_n,
+            r: self.scrypt_r,
+            p: self.scrypt_p,
+        }
+    }
+
+    fn get_password(&self) -> Result<Zeroizing<String>> {
+        let password = match &self.new_password_file {
+            Some(password_file) => read_password_file(password_file)?,
+            None => Password::with_theme(&ColorfulTheme::default())
+                .with_prompt("Enter new vault Password")
+                .with_confirmation("Repeat new vault Password", "Passwords don't match")
+                .interact()
+                .map(Zeroizing::new)
+                .map_err(|e| eyre!("Failed to get password: {}", e))?,
+        };
+        if password.is_empty() {
+            return Err(eyre!("The new password is empty"));
+        }
+        Ok(password)
+    }
 }
 
 #[derive(Args)]
 struct EntryFilter {
-    #[clap(short, long, num_args(1..), help = "Filter by ISSUER")]
+    #[clap(short, long, help = "Filter by ISSUER, repeat to allow several")]
+    issuer: Vec<String>,
+    #[clap(short, long, help = "Filter by NAME, repeat to allow several")]
+    name: Vec<String>,
+    #[clap(long, help = "Filter by NOTE, repeat to allow several")]
+    note: Vec<String>,
+    #[clap(
+        short,
+        long,
+        help = "Filter by the whole GROUP name (unless --match regex), repeat to allow several"
+    )]
+    group: Vec<String>,
+    #[clap(long, help = "Filter by UUID, repeat to allow several")]
+    uuid: Vec<String>,
+    #[clap(
+        long,
+        value_name = "ISSUER",
+        help = "Leave out entries by ISSUER, repeat to leave out several"
+    )]
+    exclude_issuer: Vec<String>,
+    #[clap(
+        long,
+        value_name = "NAME",
+        help = "Leave out entries by NAME, repeat to leave out several"
+    )]
+    exclude_name: Vec<String>,
+    #[clap(
+        long = "match",
+        value_enum,
+        value_name = "MODE",
+        default_value_t = MatchMode::Substring,
+        help = "How the filters match"
+    )]
+    match_mode: MatchMode,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum MatchMode {
+    /// Part of the value, ignoring case
+    Substring,
+    /// The whole value, ignoring case
+    Exact,
+    /// Regular expression, ignoring case unless it starts with (?-i)
+    Regex,
+}
+
+/// Filter value prepared for its match mode
+enum Pattern {
+    Substring(String),
+    Exact(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    fn new(value: &str, mode: MatchMode) -> Result<Self> {
+        Ok(match mode {
+            MatchMode::Substring => Pattern::Substring(value.to_lowercase()),
+            MatchMode::Exact => Pattern::Exact(value.to_lowercase()),
+            MatchMode::Regex => Pattern::Regex(
+                RegexBuilder::new(value)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| eyre!("Invalid regex {}: {}", value, e))?,
+            ),
+        })
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        match self {
+            Pattern::Substring(value) => text.to_lowercase().contains(value),
+            Pattern::Exact(value) => text.to_lowercase() == *value,
+            Pattern::Regex(regex) => regex.is_match(text),
+        }
+    }
+}
+
+/// [`EntryFilter`] with its values prepared for the match mode
+struct EntryMatcher {
+    issuer: Vec<Pattern>,
+    name: Vec<Pattern>,
+    note: Vec<Pattern>,
+    group: Vec<Pattern>,
+    uuid: Vec<Pattern>,
+    exclude_issuer: Vec<Pattern>,
+    exclude_name: Vec<Pattern>,
+}
+
+impl EntryFilter {
+    fn matcher(&self) -> Result<EntryMatcher> {
+        let patterns = |values: &[String], mode| {
+            values
+                .iter()
+                .map(|value| Pattern::new(value, mode))
+                .collect::<Result<Vec<Pattern>>>()
+        };
+        // Group names are short and often part of other names, like "work" of "network", so
+        // they only match as a whole unless matched by regex
+        let group_mode = match self.match_mode {
+            MatchMode::Substring => MatchMode::Exact,
+            mode => mode,
+        };
+        Ok(EntryMatcher {
+            issuer: patterns(&self.issuer, self.match_mode)?,
+            name: patterns(&self.name, self.match_mode)?,
+            note: patterns(&self.note, self.match_mode)?,
+            group: patterns(&self.group, group_mode)?,
+            uuid: patterns(&self.uuid, self.match_mode)?,
+            exclude_issuer: patterns(&self.exclude_issuer, self.match_mode)?,
+            exclude_name: patterns(&self.exclude_name, self.match_mode)?,
+        })
+    }
+}
+
+impl EntryMatcher {
+    /// Whether the entry matches one of the values of each filter and none of the left out
+    /// values, with `group_names` from [`Vault::group_names`]
+    fn matches(&self, entry: &Entry, group_names: &HashMap<String, String>) -> bool {
+        let groups: Vec<&str> = entry
+            .groups
+            .iter()
+            .filter_map(|uuid| group_names.get(uuid))
+            .chain(&entry.group)
+            .map(String::as_str)
+            .collect();
+        any_matches(&self.issuer, &[&entry.issuer])
+            && any_matches(&self.name, &[&entry.name])
+            && any_matches(&self.note, &[&entry.note])
+            && any_matches(&self.group, &groups)
+            && any_matches(&self.uuid, &[&entry.uuid])
+            && !self.exclude_issuer.iter().any(|p| p.matches(&entry.issuer))
+            && !self.exclude_name.iter().any(|p| p.matches(&entry.name))
+    }
+}
+
+/// Whether no patterns are given or one of them matches one of the texts
+fn any_matches(patterns: &[Pattern], texts: &[&str]) -> bool {
+    patterns.is_empty()
+        || patterns
+            .iter()
+            .any(|pattern| texts.iter().any(|text| pattern.matches(text)))
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SortOrder {
+    /// Most recently selected first, then in the order of the vault
+    Recent,
+    /// Most often selected first, then in the order of the vault
+    Usage,
+    /// Order of the vault, as arranged in Aegis
+    Vault,
+    /// By issuer, then by name
+    Issuer,
+    /// By name, then by issuer
+    Name,
+}
+
+impl SortOrder {
+    /// Sort the entries, keeping favorites first
+    fn sort(self, entries: &mut [Entry], usage: &Usage) {
+        match self {
+            SortOrder::Recent => {
+                entries.sort_by_key(|entry| Reverse(usage.get(&entry.uuid).last_used))
+            }
+            SortOrder::Usage => entries.sort_by_key(|entry| Reverse(usage.get(&entry.uuid).count)),
+            SortOrder::Vault => {}
+            SortOrder::Issuer => entries.sort_by_cached_key(|entry| {
+                (entry.issuer.to_lowercase(), entry.name.to_lowercase())
+            }),
+            SortOrder::Name => entries.sort_by_cached_key(|entry| {
+                (entry.name.to_lowercase(), entry.issuer.to_lowercase())
+            }),
+        }
+        entries.sort_by_key(|entry| !entry.favorite);
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum EntryType {
+    Totp,
+    Hotp,
+    Steam,
+    Motp,
+    Yandex,
+}
+
+#[derive(Args)]
+struct NewEntry {
+    #[clap(
+        long,
+        help = "otpauth:// URI with the parameters of the entry",
+        conflicts_with_all = ["secret", "entry_type", "algo", "digits", "period", "counter"]
+    )]
+    uri: Option<String>,
+    #[clap(
+        long,
+        value_name = "IMAGE",
+        help = "IMAGE file with QR codes of otpauth:// or otpauth-migration:// URIs",
+        conflicts_with_all = ["uri", "secret", "entry_type", "algo", "digits", "period", "counter"]
+    )]
+    qr: Option<PathBuf>,
+    #[clap(
+        long,
+        required_unless_present_any = ["uri", "qr"],
+        help = "ISSUER (service) of the entry"
+    )]
     issuer: Option<String>,
-    #[clap(short, long, num_args(1..), help = "Filter by NAME")]
+    #[clap(
+        long,
+        required_unless_present_any = ["uri", "qr"],
+        help = "NAME (account) of the entry"
+    )]
     name: Option<String>,
+    #[clap(long, required_unless_present_any = ["uri", "qr"], help = "Base32 encoded SECRET")]
+    secret: Option<String>,
+    #[clap(long = "type", value_enum, default_value_t = EntryType::Totp, help = "TYPE of the entry")]
+    entry_type: EntryType,
+    #[clap(
+        long,
+        help = "Hashing ALGO of TOTP and HOTP entries: SHA1, SHA256 or SHA512"
+    )]
+    algo: Option<HashAlgorithm>,
+    #[clap(long, help = "Number of DIGITS of TOTP and HOTP codes")]
+    digits: Option<i32>,
+    #[clap(long, help = "PERIOD in seconds of TOTP codes")]
+    period: Option<i32>,
+    #[clap(long, default_value_t = 0, help = "COUNTER of HOTP entries")]
+    counter: u64,
+    #[clap(
+        long,
+        help = "PIN of mOTP and Yandex entries, asked for on use when not stored"
+    )]
+    pin: Option<String>,
+    #[clap(long, default_value = "", help = "Personal NOTE")]
+    note: String,
 }
 
-impl EntryFilter {
-    fn matches(&self, entry: &Entry) -> bool {
-        if let Some(issuer) = &self.issuer {
-            if !entry.issuer.to_lowercase().contains(&issuer.to_lowercase()) {
-                return false;
+impl NewEntry {
+    fn to_entries(&self) -> Result<Vec<Entry>> {
+        let mut entries = match (&self.uri, &self.qr) {
+            (Some(uri), _) => vec![uri::parse_otpauth(uri)?],
+            (_, Some(image)) => qr::decode_image(image)?
+                .iter()
+                .map(|content| uri::parse_qr_content(content))
+                .collect::<Result<Vec<Vec<Entry>>>>()?
+                .concat(),
+            _ => vec![Entry::new(self.to_info()?, "", "")],
+        };
+        for entry in entries.iter_mut() {
+            if let Some(issuer) = &self.issuer {
+                entry.issuer = issuer.clone();
+            }
+            if let Some(name) = &self.name {
+                entry.name = name.clone();
+            }
+            if let (Some(pin), Some(entry_pin)) = (&self.pin, entry.info.pin_mut()) {
+                *entry_pin = Some(pin.clone());
+            }
+            entry.note = self.note.clone();
+            entry.info.validate()?;
+        }
+        Ok(entries)
+    }
+
+    fn to_info(&self) -> Result<EntryInfo> {
+        let secret = normalize_secret(self.secret.as_deref().unwrap_or_default())?;
+        Ok(match self.entry_type {
+            EntryType::Totp => EntryInfo::Totp(EntryInfoTotp {
+                secret,
+                algo: self.algo.unwrap_or(HashAlgorithm::Sha1),
+                digits: self.digits.unwrap_or(6),
+                period: self.period.unwrap_or(30),
+            }),
+            EntryType::Hotp => EntryInfo::Hotp(EntryInfoHotp {
+                secret,
+                algo: self.algo.unwrap_or(HashAlgorithm::Sha1),
+                digits: self.digits.unwrap_or(6),
+                counter: self.counter,
+            }),
+            EntryType::Steam => EntryInfo::Steam(EntryInfoSteam {
+                secret,
+                algo: HashAlgorithm::Sha1,
+                digits: 5,
+                period: 30,
+            }),
+            EntryType::Motp => EntryInfo::Motp(EntryInfoMotp {
+                secret,
+                algo: HashAlgorithm::Md5,
+                digits: 6,
+                period: 10,
+                pin: None,
+            }),
+            EntryType::Yandex => EntryInfo::Yandex(EntryInfoYandex {
+                secret,
+                algo: HashAlgorithm::Sha256,
+                digits: 8,
+                period: 30,
+                pin: None,
+            }),
+        })
+    }
+}
+
+#[derive(Args)]
+struct Validity {
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        value_parser = clap::value_parser!(i32).range(0..),
+        help = "Wait for the next code when the current one is valid for less than SECONDS"
+    )]
+    min_valid: Option<i32>,
+    #[clap(
+        long,
+        requires = "min_valid",
+        help = "Give the upcoming code instead of waiting for it (with --min-valid)"
+    )]
+    next: bool,
+}
+
+impl Validity {
+    /// Make sure that codes of the time based entries can be valid for `min_valid` seconds, before
+    /// any code is generated
+    fn check(&self, entries: &[Entry]) -> Result<()> {
+        let Some(min_valid) = self.min_valid else {
+            return Ok(());
+        };
+        for entry in entries {
+            match entry.info.period() {
+                Some(period) if min_valid > period => {
+                    return Err(eyre!(
+                        "--min-valid {} is longer than the period of {}s of {} ({})",
+                        min_valid,
+                        period,
+                        entry.issuer.trim(),
+                        entry.name.trim()
+                    ))
+                }
+                _ => {}
             }
         }
-        if let Some(name) = &self.name {
-            if !entry.name.to_lowercase().contains(&name.to_lowercase()) {
-                return false;
+        Ok(())
+    }
+
+    /// First time since the UNIX epoch from `now` on at which the codes of all time based entries
+    /// stay valid for at least `min_valid` seconds
+    fn valid_from(&self, entries: &[Entry], now: i64) -> Result<i64> {
+        let Some(min_valid) = self.min_valid else {
+            return Ok(now);
+        };
+        // Skip to the next period of a code that expires too soon until none does, at the latest
+        // that is when the periods of all entries start together
+        let mut time = now;
+        'search: loop {
+            for entry in entries.iter().filter(|entry| entry.info.period().is_some()) {
+                let remaining_time = calculate_remaining_time_at(&entry.info, time)?;
+                if remaining_time < min_valid {
+                    time += remaining_time as i64;
+                    continue 'search;
+                }
             }
+            return Ok(time);
+        }
+    }
+
+    /// Times since the UNIX epoch to generate the codes of the entries for, so that they stay
+    /// valid for at least `min_valid` seconds
+    ///
+    /// There is a single wait for all entries, so that no code runs out while waiting for another.
+    /// With `next` every entry gets its upcoming code instead.
+    fn code_times(&self, entries: &[Entry]) -> Result<Vec<i64>> {
+        let now = time_since_epoch();
+        if self.next {
+            return entries
+                .iter()
+                .map(|entry| self.valid_from(slice::from_ref(entry), now))
+                .collect();
         }
-        true
+        let time = self.valid_from(entries, now)?;
+        if time > now {
+            thread::sleep(Duration::from_secs((time - now) as u64));
+        }
+        Ok(vec![time_since_epoch(); entries.len()])
+    }
+}
+
+#[derive(Args)]
+struct ClipboardOptions {
+    #[clap(
+        long = "clipboard",
+        value_enum,
+        value_name = "MODE",
+        env = "AEGIS_CLIPBOARD",
+        default_value_t = clipboard::Mode::Clipboard,
+        help = "Where the code of the selected entry goes"
+    )]
+    mode: clipboard::Mode,
+    #[clap(
+        long,
+        value_name = "SECONDS",
+        env = "AEGIS_CLIPBOARD_TIMEOUT",
+        default_value_t = 30,
+        help = "Clear the copied code from the clipboard after SECONDS, 0 to keep it until the code is closed"
+    )]
+    clipboard_timeout: u64,
+}
+
+impl ClipboardOptions {
+    fn clear_after(&self) -> Option<Duration> {
+        (self.clipboard_timeout > 0).then(|| Duration::from_secs(self.clipboard_timeout))
     }
 }
 
@@ -76,26 +760,185 @@ impl EntryFilter {
 struct CalculatedOtp {
     issuer: String,
     name: String,
-    otp: String,
-    remaining_time: i32,
+    /// `None` for entries that need a PIN which is not stored in the vault
+    #[serde(skip_serializing_if = "Option::is_none")]
+    otp: Option<Zeroizing<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remaining_time: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    valid_from: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    valid_until: Option<i64>,
 }
 
 impl PasswordGetter for PasswordInput {
-    fn get_password(&self) -> Result<String> {
-        match (&self.password, &self.password_file) {
-            (Some(password), None) => Ok(password.clone()),
-            (None, Some(password_file)) => {
-                let password = fs::read_to_string(password_file)?;
-                Ok(password.trim().to_string())
-            }
+    fn get_password(&self) -> Result<Zeroizing<String>> {
+        match (&self.password, &self.password_file, &self.password_command) {
+            (Some(password), None, None) => Ok(password.clone()),
+            (None, Some(password_file), None) => read_password_file(password_file),
+            (None, None, Some(command)) => run_password_command(command),
             _ => Password::with_theme(&ColorfulTheme::default())
                 .with_prompt("Enter Aegis vault Password")
                 .interact()
+                .map(Zeroizing::new)
                 .map_err(|e| eyre!("Failed to get password: {}", e)),
         }
     }
 }
 
+/// Keeps a secret given on the command line in memory that is wiped when it is dropped
+fn parse_secret(value: &str) -> Result<Zeroizing<String>, Infallible> {
+    Ok(Zeroizing::new(value.to_string()))
+}
+
+/// Reads a password file, without the surrounding whitespace
+fn read_password_file(password_file: &Path) -> Result<Zeroizing<String>> {
+    let contents = Zeroizing::new(fs::read_to_string(password_file)?);
+    Ok(Zeroizing::new(contents.trim().to_string()))
+}
+
+/// Runs the password command in the shell and returns the first line of its output
+///
+/// Stdin and stderr are left to the command, so that tools like `pass` or `gpg` can ask for a
+/// passphrase. The command runs in its own process group, which is killed as a whole when the
+/// command does not print the password and exit within the timeout.
+fn run_password_command(command: &str) -> Result<Zeroizing<String>> {
+    let (shell, flag) = if cfg!(windows) {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    };
+    let mut shell_command = process::Command::new(shell);
+    shell_command
+        .args([flag, command])
+        .stdout(process::Stdio::piped());
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        shell_command.process_group(0);
+    }
+    let mut child = shell_command
+        .spawn()
+        .map_err(|e| eyre!("Failed to run password command: {}", e))?;
+    #[cfg(unix)]
+    let _foreground = ForegroundGroup::set(child.id());
+
+    // Read the first line in a thread, the rest is drained so that a command with a lot of
+    // output can't block on a full pipe. Processes left in the background may keep the pipe
+    // open, so the end of the output is not waited for.
+    let mut stdout = child.stdout.take().expect("Piped stdout");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut line = Zeroizing::new(Vec::with_capacity(1024));
+        let mut byte = [0u8];
+        let result = loop {
+            match stdout.read(&mut byte) {
+                Ok(0) => break Ok(line),
+                Ok(_) if byte[0] == b'\n' => break Ok(line),
+                Ok(_) => line.push(byte[0]),
+                Err(e) => break Err(e),
+            }
+        };
+        let _ = tx.send(result);
+        let _ = io::copy(&mut stdout, &mut io::sink());
+    });
+
+    let deadline = Instant::now() + PASSWORD_COMMAND_TIMEOUT;
+    let line = rx.recv_timeout(PASSWORD_COMMAND_TIMEOUT);
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if line.is_err() || Instant::now() >= deadline {
+            break None;
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let (Ok(line), Some(status)) = (line, status) else {
+        kill_password_command(&mut child);
+        return Err(eyre!(
+            "Password command timed out after {}s",
+            PASSWORD_COMMAND_TIMEOUT.as_secs()
+        ));
+    };
+    if !status.success() {
+        return Err(eyre!("Password command failed: {}", status));
+    }
+    let line = line.map_err(|e| eyre!("Failed to read password command output: {}", e))?;
+    let password = std::str::from_utf8(&line)
+        .map_err(|_| eyre!("Password command printed no valid UTF-8"))?
+        .trim_end_matches('\r');
+    if password.is_empty() {
+        return Err(eyre!("Password command printed no password"));
+    }
+    Ok(Zeroizing::new(password.to_string()))
+}
+
+/// Kill the password command together with the processes it started
+fn kill_password_command(child: &mut process::Child) {
+    #[cfg(unix)]
+    // SAFETY: kill has no memory safety preconditions, the child is the leader of its group
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    let _ = child.kill();
+    let _ = child.wait();
+}
+
+/// Terminal handed to the process group of the password command while it runs, so that it
+/// can still ask for a passphrase on the terminal from its own process group
+#[cfg(unix)]
+struct ForegroundGroup {
+    terminal: fs::File,
+    previous: libc::pid_t,
+}
+
+#[cfg(unix)]
+impl ForegroundGroup {
+    /// Make the group `pgid` the foreground process group, when we are in the foreground of
+    /// a controlling terminal
+    fn set(pgid: u32) -> Option<Self> {
+        use std::os::fd::AsRawFd;
+        let terminal = fs::File::open("/dev/tty").ok()?;
+        let fd = terminal.as_raw_fd();
+        // SAFETY: the calls have no memory safety preconditions and `fd` is open
+        unsafe {
+            let previous = libc::tcgetpgrp(fd);
+            if previous != libc::getpgrp() || libc::tcsetpgrp(fd, pgid as libc::pid_t) != 0 {
+                return None;
+            }
+            // The command may have been stopped by reading from the terminal before it was
+            // handed over
+            libc::kill(-(pgid as libc::pid_t), libc::SIGCONT);
+            Some(Self { terminal, previous })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for ForegroundGroup {
+    fn drop(&mut self) {
+        use std::os::fd::AsRawFd;
+        // SAFETY: as above, SIGTTOU is ignored since we are in the background at this point
+        unsafe {
+            let handler = libc::signal(libc::SIGTTOU, libc::SIG_IGN);
+            libc::tcsetpgrp(self.terminal.as_raw_fd(), self.previous);
+            libc::signal(libc::SIGTTOU, handler);
+        }
+    }
+}
+
+/// Password that is already known, like one remembered in the Secret Service
+#[cfg(target_os = "linux")]
+struct KnownPassword(Zeroizing<String>);
+
+#[cfg(target_os = "linux")]
+impl PasswordGetter for KnownPassword {
+    fn get_password(&self) -> Result<Zeroizing<String>> {
+        Ok(self.0.clone())
+    }
+}
+
 fn set_sigint_hook() {
     ctrlc::set_handler(move || {
         Term::stdout().show_cursor().expect("Showing cursor");
@@ -103,7 +946,49 @@ fn set_sigint_hook() {
     .expect("Setting SIGINT handler");
 }
 
-fn print_otp_every_second(entry_info: &EntryInfo) -> Result<()> {
+/// Generates the code for an entry, advancing the counter of HOTP entries in the vault
+///
+/// The vault has to be saved afterwards, so that other devices stay in sync.
+fn next_otp(vault: &mut Vault, entry: &mut Entry) -> Result<Zeroizing<String>> {
+    let otp_code = generate_otp(&entry.info)?;
+    if let EntryInfo::Hotp(info) = &mut entry.info {
+        info.counter += 1;
+        vault.update_entry(entry)?;
+    }
+    Ok(otp_code)
+}
+
+/// Prompts for the PIN of mOTP and Yandex entries that do not store one in the vault
+fn ensure_pin(entry: &mut Entry) -> Result<()> {
+    if let Some(pin @ None) = entry.info.pin_mut() {
+        let input = Password::with_theme(&ColorfulTheme::default())
+            .with_prompt(format!(
+                "Enter PIN for {} ({})",
+                entry.issuer.trim(),
+                entry.name.trim()
+            ))
+            .report(false)
+            .interact()
+            .map_err(|e| eyre!("Failed to get PIN: {}", e))?;
+        *pin = Some(input);
+    }
+    Ok(())
+}
+
+/// Color of a code by how long it stays valid
+fn remaining_time_style(remaining_time: i32) -> Style {
+    match remaining_time {
+        0..=5 => Style::new().red(),
+        6..=15 => Style::new().yellow(),
+        _ => Style::new().green(),
+    }
+}
+
+fn print_otp_every_second(
+    vault: &mut Vault,
+    entry: &mut Entry,
+    clipboard_options: &ClipboardOptions,
+) -> Result<()> {
     let term = Term::stdout();
     term.hide_cursor()?;
     let (tx, rx) = mpsc::channel();
@@ -112,45 +997,103 @@ fn print_otp_every_second(entry_info: &EntryInfo) -> Result<()> {
         let term = Term::stdout();
         loop {
             if let Ok(key) = term.read_key() {
-                if key == Key::Escape {
-                    let _ = tx.send(());
+                let escape = key == Key::Escape;
+                if tx.send(key).is_err() || escape {
                     break;
                 }
             }
         }
     });
 
-    let mut clipboard = arboard::Clipboard::new().ok();
-    let mut otp_code = String::new();
+    let mut clipboard =
+        CodeClipboard::new(clipboard_options.mode, clipboard_options.clear_after())?;
+    let mut otp_code = Zeroizing::new(String::new());
+    let mut next_code: Option<Zeroizing<String>> = None;
+    let mut next_code_copied = false;
     let mut last_remaining_time = 0;
 
+    if let EntryInfo::Hotp(info) = &entry.info {
+        let counter = info.counter;
+        otp_code = next_otp(vault, entry)?;
+        vault.save()?;
+        clipboard.copy(&otp_code)?;
+        let line = Style::new().cyan().bold().apply_to(format!(
+            "{} (counter {})",
+            otp_code.as_str(),
+            counter
+        ));
+        term.write_line(Zeroizing::new(line.to_string()).as_str())?;
+        // The code only changes on request, so just wait for the Escape key
+        loop {
+            match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok(Key::Escape) | Err(RecvTimeoutError::Disconnected) => break,
+                Ok(_) | Err(RecvTimeoutError::Timeout) => clipboard.clear_if_timed_out()?,
+            }
+        }
+        clipboard.clear()?;
+        term.clear_last_lines(1)?;
+        term.show_cursor()?;
+        return Ok(());
+    }
+
     loop {
         match rx.try_recv() {
-            Ok(_) | Err(TryRecvError::Disconnected) => {
+            Ok(Key::Escape) | Err(TryRecvError::Disconnected) => {
+                clipboard.clear()?;
                 term.clear_last_lines(1)?;
                 term.show_cursor()?;
                 break;
             }
-            Err(TryRecvError::Empty) => {}
+            Ok(Key::Char('n')) => {
+                if let Some(next_code) = &next_code {
+                    clipboard.copy(next_code)?;
+                    next_code_copied = clipboard.is_available();
+                }
+            }
+            Ok(_) | Err(TryRecvError::Empty) => {}
         }
 
-        let remaining_time = calculate_remaining_time(entry_info)?;
+        let remaining_time = calculate_remaining_time(&entry.info)?;
         if last_remaining_time < remaining_time {
-            otp_code = generate_otp(entry_info)?;
-            if let Some(clipboard) = clipboard.as_mut() {
-                clipboard.set_text(otp_code.clone())?;
+            otp_code = generate_otp(&entry.info)?;
+            if last_remaining_time == 0 {
+                clipboard.copy(&otp_code)?;
+            } else {
+                clipboard.refresh(&otp_code)?;
             }
+            next_code = None;
+            next_code_copied = false;
         }
+        if remaining_time <= NEXT_CODE_TIME && next_code.is_none() {
+            let next_period = time_since_epoch() + remaining_time as i64;
+            next_code = Some(generate_otp_at(&entry.info, next_period)?);
+        }
+        clipboard.clear_if_timed_out()?;
 
-        let style = match remaining_time {
-            0..=5 => Style::new().red(),
-            6..=15 => Style::new().yellow(),
-            _ => Style::new().green(),
-        };
-        let line = style
-            .bold()
-            .apply_to(format!("{} ({}s left)", otp_code, remaining_time));
-        term.write_line(line.to_string().as_str())?;
+        let style = remaining_time_style(remaining_time).bold();
+        // Large enough up front, so that no copy of the codes is left behind by reallocating
+        let mut line = Zeroizing::new(String::with_capacity(256));
+        write!(
+            line,
+            "{} {}",
+            style.apply_to(otp_code.as_str()),
+            style.apply_to(format!("({}s left)", remaining_time))
+        )?;
+        if let Some(next_code) = &next_code {
+            let hint = match (next_code_copied, clipboard.is_available()) {
+                (true, _) => " (copied)",
+                (false, true) => " (n to copy)",
+                (false, false) => "",
+            };
+            write!(
+                line,
+                "  {} {}{}",
+                Style::new().dim().apply_to("next:"),
+                Style::new().cyan().bold().apply_to(next_code.as_str()),
+                Style::new().dim().apply_to(hint)
+            )?;
+        }
+        term.write_line(&line)?;
         std::thread::sleep(Duration::from_millis(60));
         term.clear_last_lines(1)?;
         last_remaining_time = remaining_time;
@@ -159,27 +1102,151 @@ fn print_otp_every_second(entry_info: &EntryInfo) -> Result<()> {
     Ok(())
 }
 
-fn entries_to_json(entries: &[Entry]) -> Result<()> {
+fn entries_to_json(vault: &mut Vault, entries: &mut [Entry], validity: &Validity) -> Result<()> {
+    validity.check(entries)?;
+    let times = validity.code_times(entries)?;
+    let mut counter_advanced = false;
     let output: Vec<CalculatedOtp> = entries
-        .iter()
-        .map(|entry| {
+        .iter_mut()
+        .zip(times)
+        .map(|(entry, time)| {
+            // Not prompting keeps the output usable in scripts
+            if let Some(None) = entry.info.pin_mut() {
+                eprintln!(
+                    "No PIN stored for {} ({}), leaving out its code",
+                    entry.issuer.trim(),
+                    entry.name.trim()
+                );
+                return Ok(CalculatedOtp {
+                    issuer: entry.issuer.clone(),
+                    name: entry.name.clone(),
+                    otp: None,
+                    remaining_time: None,
+                    valid_from: None,
+                    valid_until: None,
+                });
+            }
+            let Some(period) = entry.info.period() else {
+                counter_advanced = true;
+                return Ok(CalculatedOtp {
+                    issuer: entry.issuer.clone(),
+                    name: entry.name.clone(),
+                    otp: Some(next_otp(vault, entry)?),
+                    remaining_time: None,
+                    valid_from: None,
+                    valid_until: None,
+                });
+            };
+            let valid_until = time + calculate_remaining_time_at(&entry.info, time)? as i64;
             Ok(CalculatedOtp {
                 issuer: entry.issuer.clone(),
                 name: entry.name.clone(),
-                otp: generate_otp(&entry.info)?,
-                remaining_time: calculate_remaining_time(&entry.info)?,
+                otp: Some(generate_otp_at(&entry.info, time)?),
+                remaining_time: Some((valid_until - time_since_epoch()) as i32),
+                valid_from: Some(valid_until - period as i64),
+                valid_until: Some(valid_until),
             })
         })
         .collect::<Result<Vec<CalculatedOtp>>>()?;
+    // One save for all entries, so that the backup still holds the vault from before the run
+    if counter_advanced {
+        vault.save()?;
+    }
     if output.is_empty() {
         println!("No entries found");
     } else {
-        println!("{}", serde_json::to_string_pretty(&output)?);
+        // Write straight to stdout instead of into a string with another copy of all codes
+        let mut stdout = io::stdout().lock();
+        serde_json::to_writer_pretty(&mut stdout, &output)?;
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+fn export_uris(entries: &[Entry], qr: bool) -> Result<()> {
+    for entry in entries {
+        let uri = uri::to_otpauth(entry);
+        println!("{} ({})", entry.issuer.trim(), entry.name.trim());
+        println!("{}", uri.as_str());
+        if qr {
+            let code = qrcode::QrCode::new(uri.as_bytes())?;
+            let image = Zeroizing::new(
+                code.render::<qrcode::render::unicode::Dense1x2>()
+                    .dark_color(qrcode::render::unicode::Dense1x2::Light)
+                    .light_color(qrcode::render::unicode::Dense1x2::Dark)
+                    .build(),
+            );
+            println!("{}", image.as_str());
+        }
+        println!();
     }
     Ok(())
 }
 
-fn fuzzy_select(entries: &[Entry]) -> Result<()> {
+/// The single entry whose issuer or name matches the QUERY of a command, like the `--issuer` and
+/// `--name` filters do in `match_mode`
+///
+/// Exits the process when no entry or more than one entry matches.
+fn select_one<'a>(
+    entries: &'a mut [Entry],
+    query: Option<&str>,
+    match_mode: MatchMode,
+) -> Result<&'a mut Entry> {
+    let query = query
+        .map(|query| Pattern::new(query, match_mode))
+        .transpose()?;
+    let mut matching: Vec<&mut Entry> = entries
+        .iter_mut()
+        .filter(|entry| {
+            query
+                .as_ref()
+                .is_none_or(|query| query.matches(&entry.issuer) || query.matches(&entry.name))
+        })
+        .collect();
+    match matching.len() {
+        1 => Ok(matching.remove(0)),
+        0 => {
+            eprintln!("No matching entries based on filters");
+            exit(1);
+        }
+        _ => {
+            eprintln!("Multiple matching entries:");
+            for entry in matching {
+                eprintln!("  {} ({})", entry.issuer.trim(), entry.name.trim());
+            }
+            exit(1);
+        }
+    }
+}
+
+fn print_code(
+    vault: &mut Vault,
+    entries: &mut [Entry],
+    query: Option<&str>,
+    match_mode: MatchMode,
+    validity: &Validity,
+) -> Result<()> {
+    let entry = select_one(entries, query, match_mode)?;
+    validity.check(slice::from_ref(entry))?;
+    ensure_pin(entry)?;
+    let otp_code = match entry.info {
+        EntryInfo::Hotp(_) => {
+            let otp_code = next_otp(vault, entry)?;
+            vault.save()?;
+            otp_code
+        }
+        _ => generate_otp_at(&entry.info, validity.code_times(slice::from_ref(entry))?[0])?,
+    };
+    println!("{}", otp_code.as_str());
+    Ok(())
+}
+
+fn fuzzy_select(
+    vault: &mut Vault,
+    entries: &mut [Entry],
+    clipboard_options: &ClipboardOptions,
+    usage: &mut Usage,
+) -> Result<()> {
     set_sigint_hook();
     let items: Vec<String> = entries
         .iter()
@@ -188,7 +1255,7 @@ fn fuzzy_select(entries: &[Entry]) -> Result<()> {
     loop {
         let selection = match FuzzySelect::with_theme(&ColorfulTheme::default())
             .items(&items)
-            .default(0)
+            .default(usage.most_recent(entries).unwrap_or(0))
             .clear(true)
             .interact_opt()
         {
@@ -201,8 +1268,18 @@ fn fuzzy_select(entries: &[Entry]) -> Result<()> {
         };
         match selection {
             Some(index) => {
-                let entry_info = &entries.get(index).unwrap().info;
-                print_otp_every_second(entry_info)?;
+                let entry = entries.get_mut(index).unwrap();
+                if let Err(e) = usage.record(&entry.uuid) {
+                    eprintln!("{}", e);
+                }
+                ensure_pin(entry)?;
+                if clipboard_options.mode == clipboard::Mode::Stdout {
+                    let otp_code = next_otp(vault, entry)?;
+                    vault.save()?;
+                    println!("{}", otp_code.as_str());
+                    return Ok(());
+                }
+                print_otp_every_second(vault, entry, clipboard_options)?;
             }
             None => {
                 // Exit on Escape key
@@ -212,31 +1289,353 @@ fn fuzzy_select(entries: &[Entry]) -> Result<()> {
     }
 }
 
+/// Refuses to overwrite an existing file, so that new files get permissions for the user only
+fn ensure_new_file(path: &Path) -> Result<()> {
+    if path.exists() {
+        return Err(eyre!("{} already exists", path.display()));
+    }
+    Ok(())
+}
+
+/// Prints the time scrypt takes for increasing N, up to the first one that exceeds the target
+/// time, and suggests the largest N within the target
+fn kdf_bench(target: Duration, r: u32, p: u32) -> Result<()> {
+    let mut best = None;
+    for log_n in MIN_BENCH_LOG_N..=MAX_BENCH_LOG_N {
+        let params = ScryptParams {
+            n: 1 << log_n,
+            r,
+            p,
+        };
+        let elapsed = vault::benchmark_kdf(&params)?;
+        println!("N = {:>8}: {} ms", params.n, elapsed.as_millis());
+        if elapsed > target {
+            break;
+        }
+        best = Some(params);
+    }
+    match best {
+        Some(params) => println!(
+            "Use --scrypt-n {} --scrypt-r {} --scrypt-p {}",
+            params.n, params.r, params.p
+        ),
+        None => println!(
+            "Even N = {} takes longer than the target",
+            1 << MIN_BENCH_LOG_N
+        ),
+    }
+    Ok(())
+}
+
+/// Opens the vault with the master key held by a running agent, or with the key file or the
+/// password otherwise
+///
+/// The agent is skipped when credentials are given explicitly, and for the commands that
+/// need to know which slot the vault was unlocked with.
+fn open_vault(args: &Cli, vault_file: &Path) -> Result<Vault> {
+    let input = &args.password_input;
+    #[cfg(unix)]
+    let credentials_given = input.password.is_some()
+        || input.password_file.is_some()
+        || input.password_command.is_some()
+        || input.key_file.is_some();
+    #[cfg(unix)]
+    if !credentials_given
+        && !matches!(
+            args.command,
+            Some(Command::Passwd { .. } | Command::Slot { .. })
+        )
+    {
+        let socket_path = match &args.agent_socket {
+            Some(socket_path) => socket_path.clone(),
+            None => agent::default_socket_path(),
+        };
+        if let Some(master_key) = agent::request_master_key(&socket_path, vault_file) {
+            if let Ok(vault) = Vault::open_with_master_key(vault_file, master_key) {
+                return Ok(vault);
+            }
+        }
+    }
+    if let Some(key_file) = &input.key_file {
+        return Vault::open_with_key_file(vault_file, key_file);
+    }
+    #[cfg(target_os = "linux")]
+    {
+        if input.keyring && !credentials_given {
+            if let Ok(Some(password)) = keyring::lookup_password(vault_file) {
+                // The remembered password is outdated when this fails, so ask again
+                if let Ok(vault) = Vault::open(vault_file, &KnownPassword(password)) {
+                    return Ok(vault);
+                }
+            }
+        }
+        if input.remember {
+            let password = input.get_password()?;
+            let vault = Vault::open(vault_file, &KnownPassword(password.clone()))?;
+            if let Err(e) = keyring::store_password(vault_file, &password) {
+                eprintln!("Failed to remember the password: {}", e);
+            }
+            return Ok(vault);
+        }
+    }
+    Vault::open(vault_file, input)
+}
+
 fn main() -> Result<()> {
     color_eyre::install()?;
 
     let args = Cli::parse();
 
-    let file_contents = match fs::read_to_string(&args.vault_file) {
-        Ok(contents) => contents,
-        Err(e) => {
-            eprintln!("Failed to read Aegis vault file: {}", e);
-            exit(1);
-        }
+    if let Some(Command::KdfBench {
+        target,
+        scrypt_r,
+        scrypt_p,
+    }) = &args.command
+    {
+        return kdf_bench(Duration::from_millis(*target), *scrypt_r, *scrypt_p);
+    }
+    let Some(vault_file) = &args.vault_file else {
+        eprintln!("The VAULT_FILE argument is required for this command");
+        exit(1);
     };
-    let entries = match parse_vault(&file_contents, &args.password_input) {
-        Ok(db) => db
-            .entries
-            .into_iter()
-            // Only TOTP entries are supported at the moment remove this filter later
-            .filter(|e| matches!(e.info, EntryInfo::Totp(_)))
-            .filter(|e| args.entry_filter.matches(e))
-            .collect::<Vec<Entry>>(),
+
+    #[cfg(target_os = "linux")]
+    if args.password_input.forget {
+        if keyring::forget_password(vault_file)? {
+            println!("Forgot the password of {}", vault_file.display());
+        } else {
+            println!("No remembered password for {}", vault_file.display());
+        }
+        return Ok(());
+    }
+
+    let mut vault = match open_vault(&args, vault_file) {
+        Ok(vault) => vault,
         Err(e) => {
             eprintln!("Failed to open Aegis vault: {}", e);
             exit(1);
         }
     };
+    let entry_matcher = args.entry_filter.matcher()?;
+    let group_names = vault.group_names();
+    let mut entries = vault
+        .entries()
+        .into_iter()
+        .filter(|e| entry_matcher.matches(e, &group_names))
+        .collect::<Vec<Entry>>();
+    let usage_file = match args.no_usage {
+        true => None,
+        false => args.usage_file.clone().or_else(usage::default_path),
+    };
+    // A broken usage file only costs the ordering, so don't stop there
+    let mut usage = usage_file
+        .map(|usage_file| Usage::load(&usage_file))
+        .transpose()
+        .unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            None
+        })
+        .unwrap_or_default();
+    // Scripts reading the output get the entries in a stable order unless asked otherwise
+    let interactive = !args.json && matches!(args.command, None | Some(Command::Dashboard));
+    if let Some(sort) = args.sort.or(interactive.then_some(SortOrder::Recent)) {
+        sort.sort(&mut entries, &usage);
+    }
+
+    match &args.command {
+        Some(Command::Code { query }) => {
+            return print_code(
+                &mut vault,
+                &mut entries,
+                query.as_deref(),
+                args.entry_filter.match_mode,
+                &args.validity,
+            );
+        }
+        Some(Command::Add(new_entry)) => {
+            let new_entries = new_entry.to_entries()?;
+            for entry in &new_entries {
+                vault.add_entry(entry)?;
+            }
+            vault.save()?;
+            for entry in &new_entries {
+                println!("Added {} ({})", entry.issuer, entry.name);
+            }
+            return Ok(());
+        }
+        Some(Command::Edit {
+            query,
+            issuer,
+            name,
+            note,
+        }) => {
+            let entry = select_one(&mut entries, query.as_deref(), args.entry_filter.match_mode)?;
+            if let Some(issuer) = issuer {
+                entry.issuer = issuer.clone();
+            }
+            if let Some(name) = name {
+                entry.name = name.clone();
+            }
+            if let Some(note) = note {
+                entry.note = note.clone();
+            }
+            vault.update_entry(entry)?;
+            vault.save()?;
+            println!("Changed {} ({})", entry.issuer, entry.name);
+            return Ok(());
+        }
+        Some(Command::Rm { query, yes }) => {
+            let entry = select_one(&mut entries, query.as_deref(), args.entry_filter.match_mode)?;
+            let confirmed = *yes
+                || Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!("Remove {} ({})?", entry.issuer, entry.name))
+                    .default(false)
+                    .interact()?;
+            if confirmed {
+                vault.remove_entry(&entry.uuid)?;
+                vault.save()?;
+                println!("Removed {} ({})", entry.issuer, entry.name);
+            }
+            return Ok(());
+        }
+        Some(Command::Import { from, file }) => {
+            let imported = import::read_entries(*from, file, || {
+                Password::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Enter export file Password")
+                    .interact()
+                    .map(Zeroizing::new)
+                    .map_err(|e| eyre!("Failed to get password: {}", e))
+            })?;
+            let (new_entries, duplicates) = import::dedup(imported, &vault.entries());
+            for entry in &new_entries {
+                vault.add_entry(entry)?;
+            }
+            if !new_entries.is_empty() {
+                vault.save()?;
+            }
+            for entry in &new_entries {
+                println!("Imported {} ({})", entry.issuer, entry.name);
+            }
+            for entry in &duplicates {
+                println!("Skipped duplicate {} ({})", entry.issuer, entry.name);
+            }
+            return Ok(());
+        }
+        Some(Command::Export {
+            plain: true,
+            out: Some(out),
+            yes,
+            ..
+        }) => {
+            ensure_new_file(out)?;
+            eprintln!(
+                "{}",
+                "WARNING: the plain text vault contains all secrets unencrypted!"
+                    .red()
+                    .bold()
+            );
+            let confirmed = *yes
+                || Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!(
+                        "Write all secrets unencrypted to {}?",
+                        out.display()
+                    ))
+                    .default(false)
+                    .interact()?;
+            if confirmed {
+                vault.save_plain(out)?;
+                println!("Wrote plain text vault {}", out.display());
+            }
+            return Ok(());
+        }
+        Some(Command::Export { qr, yes, .. }) => {
+            if entries.is_empty() {
+                println!("No matching entries based on filters");
+                return Ok(());
+            }
+            let confirmed = *yes
+                || Confirm::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!("Display the secrets of {} entries?", entries.len()))
+                    .default(false)
+                    .interact()?;
+            if confirmed {
+                export_uris(&entries, *qr)?;
+            }
+            return Ok(());
+        }
+        Some(Command::Encrypt { out, new_password }) => {
+            ensure_new_file(out)?;
+            vault.encrypt(&new_password.get_password()?, new_password.scrypt_params())?;
+            vault.save_as(out)?;
+            println!("Wrote encrypted vault {}", out.display());
+            return Ok(());
+        }
+        Some(Command::Passwd { new_password }) => {
+            vault.change_password(&new_password.get_password()?, new_password.scrypt_params())?;
+            vault.save()?;
+            println!("Changed the vault password");
+            return Ok(());
+        }
+        Some(Command::Slot { command }) => {
+            match command {
+                SlotCommand::List => {
+                    for slot in vault.slots() {
+                        let unlocked = if slot.unlocked { " (unlocked)" } else { "" };
+                        println!("{} {}{}", slot.uuid, slot.slot_type, unlocked);
+                    }
+                }
+                SlotCommand::Add { new_password } => {
+                    let uuid = vault.add_password_slot(
+                        &new_password.get_password()?,
+                        new_password.scrypt_params(),
+                    )?;
+                    vault.save()?;
+                    println!("Added password slot {}", uuid);
+                }
+                SlotCommand::AddKeyFile { file } => {
+                    let uuid = vault.add_key_file_slot(file)?;
+                    vault.save()?;
+                    println!("Added key file slot {} for {}", uuid, file.display());
+                }
+                SlotCommand::Rm { uuid, yes } => {
+                    let confirmed = *yes
+                        || Confirm::with_theme(&ColorfulTheme::default())
+                            .with_prompt(format!("Remove slot {}?", uuid))
+                            .default(false)
+                            .interact()?;
+                    if confirmed {
+                        vault.remove_slot(uuid)?;
+                        vault.save()?;
+                        println!("Removed slot {}", uuid);
+                    }
+                }
+            }
+            return Ok(());
+        }
+        #[cfg(unix)]
+        Some(Command::Agent { idle_timeout }) => {
+            let socket_path = match &args.agent_socket {
+                Some(socket_path) => socket_path.clone(),
+                None => agent::default_socket_path(),
+            };
+            let master_key = Zeroizing::new(
+                vault
+                    .master_key()
+                    .ok_or(eyre!("The vault is not encrypted"))?
+                    .to_vec(),
+            );
+            // Only the copy of the key in locked memory stays around while the agent runs
+            drop(vault);
+            drop(entries);
+            return agent::run(
+                &socket_path,
+                vault_file,
+                master_key,
+                Duration::from_secs(*idle_timeout),
+            );
+        }
+        Some(Command::KdfBench { .. } | Command::Dashboard) | None => {}
+    }
 
     if entries.is_empty() {
         println!("No matching entries based on filters");
@@ -244,10 +1643,245 @@ fn main() -> Result<()> {
     }
 
     if args.json {
-        entries_to_json(&entries)?;
+        entries_to_json(&mut vault, &mut entries, &args.validity)?;
+    } else if let Some(Command::Dashboard) = args.command {
+        set_sigint_hook();
+        dashboard::run(&mut vault, &mut entries, &args.clipboard)?;
     } else {
-        fuzzy_select(&entries)?;
+        fuzzy_select(&mut vault, &mut entries, &args.clipboard, &mut usage)?;
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use otp::{EntryInfoHotp, EntryInfoTotp, HashAlgorithm};
+
+    fn totp_entry(period: i32) -> Entry {
+        Entry::new(
+            EntryInfo::Totp(EntryInfoTotp {
+                secret: "4SJHB4GSD43FZBAI7C2HLRJGPQ".to_string(),
+                algo: HashAlgorithm::Sha1,
+                digits: 6,
+                period,
+            }),
+            "Deno",
+            "Mason",
+        )
+    }
+
+    fn hotp_entry() -> Entry {
+        Entry::new(
+            EntryInfo::Hotp(EntryInfoHotp {
+                secret: "YOOMIXWS5GN6RTBPUFFWKTW5M4".to_string(),
+                algo: HashAlgorithm::Sha1,
+                digits: 6,
+                counter: 1,
+            }),
+            "Issuu",
+            "James",
+        )
+    }
+
+    #[test]
+    fn sort_orders_keep_favorites_first() {
+        let mut entries = [totp_entry(30), hotp_entry(), totp_entry(30)];
+        entries[2].issuer = "Airbnb".to_string();
+        entries[2].name = "Elijah".to_string();
+        entries[2].favorite = true;
+        let uuids: Vec<String> = entries.iter().map(|entry| entry.uuid.clone()).collect();
+        let usage_file = env::temp_dir().join(format!("aegis-usage-{}.json", process::id()));
+        fs::write(
+            &usage_file,
+            serde_json::json!({ "entries": {
+                &uuids[0]: { "count": 1, "last_used": 200 },
+                &uuids[1]: { "count": 5, "last_used": 100 },
+            }})
+            .to_string(),
+        )
+        .unwrap();
+        let usage = Usage::load(&usage_file).unwrap();
+        fs::remove_file(&usage_file).unwrap();
+
+        let order = |sort: SortOrder| {
+            let mut sorted = entries.clone();
+            sort.sort(&mut sorted, &usage);
+            sorted
+                .iter()
+                .map(|entry| uuids.iter().position(|uuid| *uuid == entry.uuid).unwrap())
+                .collect::<Vec<usize>>()
+        };
+        assert_eq!(order(SortOrder::Vault), [2, 0, 1]);
+        assert_eq!(order(SortOrder::Recent), [2, 0, 1]);
+        assert_eq!(order(SortOrder::Usage), [2, 1, 0]);
+        assert_eq!(order(SortOrder::Issuer), [2, 0, 1]);
+        assert_eq!(order(SortOrder::Name), [2, 1, 0]);
+    }
+
+    fn matcher(args: &[&str]) -> EntryMatcher {
+        Cli::try_parse_from(["aegis"].iter().chain(args).chain(&["vault.json"]))
+            .unwrap()
+            .entry_filter
+            .matcher()
+            .unwrap()
+    }
+
+    #[test]
+    fn groups_match_whole_names() {
+        let group_names = HashMap::from([
+            ("g1".to_string(), "Work".to_string()),
+            ("g2".to_string(), "Network".to_string()),
+        ]);
+        let mut work = totp_entry(30);
+        work.groups = vec!["g1".to_string()];
+        let mut network = totp_entry(30);
+        network.groups = vec!["g2".to_string()];
+
+        let by_group = matcher(&["-g", "work"]);
+        assert!(by_group.matches(&work, &group_names));
+        assert!(!by_group.matches(&network, &group_names));
+        assert!(!matcher(&["-g", "wor"]).matches(&work, &group_names));
+        let by_regex = matcher(&["--match", "regex", "-g", "work$"]);
+        assert!(by_regex.matches(&work, &group_names));
+        assert!(by_regex.matches(&network, &group_names));
+        // Other filters still match parts of the value
+        assert!(matcher(&["-i", "den"]).matches(&work, &group_names));
+    }
+
+    #[test]
+    fn query_uses_match_mode() {
+        let mut entries = [totp_entry(30), hotp_entry()];
+        entries[1].issuer = "Deno Land".to_string();
+        let uuid = entries[0].uuid.clone();
+        let selected = select_one(&mut entries, Some("DENO"), MatchMode::Exact).unwrap();
+        assert_eq!(selected.uuid, uuid);
+        let selected = select_one(&mut entries, Some("^den.$"), MatchMode::Regex).unwrap();
+        assert_eq!(selected.uuid, uuid);
+        assert!(select_one(&mut entries, Some("("), MatchMode::Regex).is_err());
+    }
+
+    #[test]
+    fn min_valid_up_to_the_period() {
+        let validity = |min_valid| Validity {
+            min_valid: Some(min_valid),
+            next: false,
+        };
+        let entries = [totp_entry(30), totp_entry(60), hotp_entry()];
+        assert!(validity(30).check(&entries).is_ok());
+        assert!(validity(45).check(&entries).is_err());
+        assert!(validity(45).check(&entries[1..]).is_ok());
+        assert!(validity(45).check(&entries[2..]).is_ok());
+    }
+
+    #[test]
+    fn min_valid_for_all_entries_at_once() {
+        let validity = |min_valid| Validity {
+            min_valid: Some(min_valid),
+            next: false,
+        };
+        // 18 seconds left of the 30 second code and 8 seconds of the 20 second one
+        let entries = [totp_entry(30), totp_entry(20), hotp_entry()];
+        let now = 12;
+        assert_eq!(validity(8).valid_from(&entries, now).unwrap(), now);
+        // Waiting for the 20 second code would leave 10 seconds for the other one, which gets too
+        // short again when waiting for it
+        assert_eq!(validity(18).valid_from(&entries, now).unwrap(), 40);
+        assert_eq!(validity(10).valid_from(&entries, now).unwrap(), 20);
+        assert_eq!(validity(18).valid_from(&entries[..1], now).unwrap(), now);
+        assert_eq!(validity(19).valid_from(&entries[..1], now).unwrap(), 30);
+        assert_eq!(validity(1).valid_from(&entries[2..], now).unwrap(), now);
+        let no_min_valid = Validity {
+            min_valid: None,
+            next: false,
+        };
+        assert_eq!(no_min_valid.valid_from(&entries, now).unwrap(), now);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn password_command_first_line() {
+        let password = run_password_command("printf 'secret\\r\\nmore\\n'").unwrap();
+        assert_eq!(password.as_str(), "secret");
+        assert!(run_password_command("true").is_err());
+        assert!(run_password_command("echo secret; exit 1").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn password_command_with_background_process() {
+        let start = Instant::now();
+        let password = run_password_command("echo secret; sleep 30 &").unwrap();
+        assert_eq!(password.as_str(), "secret");
+        assert!(start.elapsed() < Duration::from_secs(10));
+    }
+
+    struct NoPassword;
+
+    impl PasswordGetter for NoPassword {
+        fn get_password(&self) -> Result<Zeroizing<String>> {
+            panic!("Password asked for a plain vault")
+        }
+    }
+
+    #[test]
+    fn json_saves_hotp_counters_once() {
+        let path = env::temp_dir().join(format!("aegis-json-hotp-{}.json", process::id()));
+        let original = include_str!("../res/aegis_plain.json");
+        fs::write(&path, original).unwrap();
+        let mut vault = Vault::open(&path, &NoPassword).unwrap();
+        let mut entries: Vec<Entry> = vault
+            .entries()
+            .into_iter()
+            .filter(|entry| matches!(entry.info, EntryInfo::Hotp(_)))
+            .collect();
+        assert_eq!(entries.len(), 3);
+        let validity = Validity {
+            min_valid: None,
+            next: false,
+        };
+        entries_to_json(&mut vault, &mut entries, &validity).unwrap();
+
+        let mut backup_path = path.as_os_str().to_owned();
+        backup_path.push(".bak");
+        let backup = fs::read_to_string(&backup_path).unwrap();
+        let counters: Vec<u64> = Vault::open(&path, &NoPassword)
+            .unwrap()
+            .entries()
+            .iter()
+            .filter_map(|entry| match &entry.info {
+                EntryInfo::Hotp(info) => Some(info.counter),
+                _ => None,
+            })
+            .collect();
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&backup_path).unwrap();
+        assert_eq!(backup, original);
+        assert_eq!(counters, [2, 51, 10301]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn keyring_lookup_is_opt_in() {
+        use clap::{CommandFactory, FromArgMatches};
+
+        let keyring = |args: &[&str], env_value: Option<&str>| {
+            match env_value {
+                Some(value) => env::set_var("AEGIS_KEYRING", value),
+                None => env::remove_var("AEGIS_KEYRING"),
+            }
+            let matches = Cli::command().try_get_matches_from(args);
+            env::remove_var("AEGIS_KEYRING");
+            Cli::from_arg_matches(&matches.unwrap())
+                .unwrap()
+                .password_input
+                .keyring
+        };
+        assert!(!keyring(&["aegis", "vault.json"], None));
+        assert!(keyring(&["aegis", "--keyring", "vault.json"], None));
+        assert!(keyring(&["aegis", "vault.json"], Some("1")));
+        assert!(!keyring(&["aegis", "vault.json"], Some("0")));
+        assert!(!keyring(&["aegis", "vault.json"], Some("false")));
+    }
+}
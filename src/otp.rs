@@ -0,0 +1,540 @@
+use color_eyre::eyre::{eyre, Result};
+use hmac::{Hmac, Mac};
+use libreauth::{
+    hash::HashFunction,
+    oath::{HOTPBuilder, TOTPBuilder},
+};
+use md5::{Digest, Md5};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+/// Hashing algorithm to use when generating the OTP
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum HashAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+    Md5,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = color_eyre::Report;
+
+    fn from_str(algo: &str) -> Result<Self> {
+        match algo.to_uppercase().as_str() {
+            "SHA1" => Ok(HashAlgorithm::Sha1),
+            "SHA256" => Ok(HashAlgorithm::Sha256),
+            "SHA512" => Ok(HashAlgorithm::Sha512),
+            "MD5" => Ok(HashAlgorithm::Md5),
+            _ => Err(eyre!("Unsupported hashing algorithm: {}", algo)),
+        }
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            HashAlgorithm::Sha1 => "SHA1",
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha512 => "SHA512",
+            HashAlgorithm::Md5 => "MD5",
+        };
+        f.write_str(name)
+    }
+}
+
+impl TryFrom<HashAlgorithm> for HashFunction {
+    type Error = color_eyre::Report;
+
+    fn try_from(algo: HashAlgorithm) -> Result<Self> {
+        match algo {
+            HashAlgorithm::Sha1 => Ok(HashFunction::Sha1),
+            HashAlgorithm::Sha256 => Ok(HashFunction::Sha256),
+            HashAlgorithm::Sha512 => Ok(HashFunction::Sha512),
+            HashAlgorithm::Md5 => Err(eyre!("MD5 is only supported for mOTP entries")),
+        }
+    }
+}
+
+/// HOTP (HMAC-based One Time Pad)
+///
+/// [RFC 4226](https://datatracker.ietf.org/doc/html/rfc4226)
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct EntryInfoHotp {
+    /// Base32 encoded secret
+    pub secret: String,
+    /// Hashing algorithm to use
+    #[zeroize(skip)]
+    pub algo: HashAlgorithm,
+    /// Number of digits in the OTP
+    pub digits: i32,
+    /// The counter value to use when generating the next OTP
+    pub counter: u64,
+}
+
+/// Time-based One Time Pads (TOTP)
+///
+/// [RFC 6238](https://datatracker.ietf.org/doc/html/rfc6238)
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct EntryInfoTotp {
+    /// Base32 encoded secret
+    pub secret: String,
+    /// Hashing algorithm to use
+    #[zeroize(skip)]
+    pub algo: HashAlgorithm,
+    /// Number of digits in the OTP
+    pub digits: i32,
+    /// The time step in seconds since the UNIX epoch
+    pub period: i32,
+}
+
+/// Steam Guard OTP
+///
+/// Essentially a TOTP with a 5 character code from the Steam alphabet and a 30 second period
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct EntryInfoSteam {
+    /// Base32 encoded secret
+    pub secret: String,
+    /// Hashing algorithm to use (always SHA1)
+    #[zeroize(skip)]
+    pub algo: HashAlgorithm,
+    /// Number of characters in the OTP (always 5)
+    pub digits: i32,
+    /// The time step in seconds since the UNIX epoch (always 30)
+    pub period: i32,
+}
+
+/// Mobile-OTP
+///
+/// MD5 hash of the time step, the hex encoded secret and the PIN, with a 10 second period
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct EntryInfoMotp {
+    /// Base32 encoded secret
+    pub secret: String,
+    /// Hashing algorithm to use (always MD5)
+    #[zeroize(skip)]
+    pub algo: HashAlgorithm,
+    /// Number of characters in the OTP (always 6)
+    pub digits: i32,
+    /// The time step in seconds since the UNIX epoch (always 10)
+    pub period: i32,
+    /// PIN that is hashed together with the secret, prompted for when not stored in the vault
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin: Option<String>,
+}
+
+/// Yandex OTP
+///
+/// HMAC-SHA256 TOTP keyed with the hash of the PIN and the secret, the code consists of letters
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Zeroize, ZeroizeOnDrop)]
+pub struct EntryInfoYandex {
+    /// Base32 encoded secret
+    pub secret: String,
+    /// Hashing algorithm to use (always SHA256)
+    #[zeroize(skip)]
+    pub algo: HashAlgorithm,
+    /// Number of letters in the OTP (always 8)
+    pub digits: i32,
+    /// The time step in seconds since the UNIX epoch (always 30)
+    pub period: i32,
+    /// PIN that is hashed together with the secret, prompted for when not stored in the vault
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pin: Option<String>,
+}
+
+/// Information used to generate one time codes
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type", content = "info")]
+pub enum EntryInfo {
+    Hotp(EntryInfoHotp),
+    Totp(EntryInfoTotp),
+    Steam(EntryInfoSteam),
+    Motp(EntryInfoMotp),
+    Yandex(EntryInfoYandex),
+}
+
+impl EntryInfo {
+    /// The base32 encoded secret
+    pub fn secret(&self) -> &str {
+        match self {
+            EntryInfo::Hotp(info) => &info.secret,
+            EntryInfo::Totp(info) => &info.secret,
+            EntryInfo::Steam(info) => &info.secret,
+            EntryInfo::Motp(info) => &info.secret,
+            EntryInfo::Yandex(info) => &info.secret,
+        }
+    }
+
+    /// The PIN of entry types that need one to generate codes, if it is known
+    pub fn pin(&self) -> Option<&str> {
+        match self {
+            EntryInfo::Motp(info) => info.pin.as_deref(),
+            EntryInfo::Yandex(info) => info.pin.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The PIN of entry types that need one to generate codes
+    pub fn pin_mut(&mut self) -> Option<&mut Option<String>> {
+        match self {
+            EntryInfo::Motp(info) => Some(&mut info.pin),
+            EntryInfo::Yandex(info) => Some(&mut info.pin),
+            _ => None,
+        }
+    }
+
+    /// Check that codes can be generated from the entry information
+    pub fn validate(&self) -> Result<()> {
+        let (secret, algo, digits) = match self {
+            EntryInfo::Hotp(info) => (&info.secret, info.algo, info.digits),
+            EntryInfo::Totp(info) => (&info.secret, info.algo, info.digits),
+            EntryInfo::Steam(info) => (&info.secret, info.algo, info.digits),
+            EntryInfo::Motp(info) => (&info.secret, info.algo, info.digits),
+            EntryInfo::Yandex(info) => (&info.secret, info.algo, info.digits),
+        };
+        decode_secret(secret)?;
+        if !matches!(self, EntryInfo::Motp(_)) {
+            HashFunction::try_from(algo)?;
+        }
+        if !(1..=10).contains(&digits) {
+            return Err(eyre!("Invalid number of digits: {}", digits));
+        }
+        if let Some(period @ ..=0) = self.period() {
+            return Err(eyre!("Invalid period: {}", period));
+        }
+        Ok(())
+    }
+
+    /// The period in seconds of time based entry types
+    pub fn period(&self) -> Option<i32> {
+        match self {
+            EntryInfo::Hotp(_) => None,
+            EntryInfo::Totp(info) => Some(info.period),
+            EntryInfo::Steam(_) => Some(30),
+            EntryInfo::Motp(info) => Some(info.period),
+            EntryInfo::Yandex(info) => Some(info.period),
+        }
+    }
+}
+
+/// Entry with metadata and information used to generate one time codes
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+pub struct Entry {
+    /// Information used to generate the OTP such as the secret and algorithm
+    #[serde(flatten)]
+    pub info: EntryInfo,
+    /// A UUID (version 4)
+    pub uuid: String,
+    /// The account name
+    pub name: String,
+    /// The service that the token is for
+    pub issuer: String,
+    /// A personal note about the entry
+    #[serde(default)]
+    pub note: String,
+    /// Favorites are listed first
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub favorite: bool,
+    /// UUIDs of the groups of the entry, since Aegis 3
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    /// Name of the single group of the entry, before Aegis 3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+impl Entry {
+    /// New entry with a fresh UUID
+    pub fn new(info: EntryInfo, issuer: &str, name: &str) -> Self {
+        Self {
+            info,
+            uuid: uuid::Uuid::new_v4().to_string(),
+            name: name.to_string(),
+            issuer: issuer.to_string(),
+            note: String::new(),
+            favorite: false,
+            groups: Vec::new(),
+            group: None,
+        }
+    }
+}
+
+/// Characters of Steam Guard codes
+const STEAM_ALPHABET: &str = "23456789BCDFGHJKMNPQRTVWXY";
+
+/// Only the first 16 bytes of a Yandex secret are key material, the rest is a checksum
+const YANDEX_SECRET_LEN: usize = 16;
+
+/// Current time since the UNIX epoch in seconds
+pub fn time_since_epoch() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs() as i64
+}
+
+/// Decode a base32 secret as stored in the vault
+fn decode_secret(secret: &str) -> Result<Zeroizing<Vec<u8>>> {
+    base32::decode(base32::Alphabet::RFC4648 { padding: false }, secret)
+        .map(Zeroizing::new)
+        .ok_or(eyre!("Invalid base32 secret"))
+}
+
+/// Encode a secret as base32 the way the vault stores it
+pub fn encode_secret(secret: &[u8]) -> String {
+    base32::encode(base32::Alphabet::RFC4648 { padding: false }, secret)
+}
+
+/// Normalize a base32 secret the way the vault stores it: upper case, without spaces and padding
+pub fn normalize_secret(secret: &str) -> Result<String> {
+    let secret: String = secret
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != '=')
+        .collect::<String>()
+        .to_uppercase();
+    if secret.is_empty() {
+        return Err(eyre!("Empty secret"));
+    }
+    decode_secret(&secret)?;
+    Ok(secret)
+}
+
+/// Generates a one time password based on the entry information and the current time
+///
+/// For HOTP entries the code for the current counter value is returned, advancing the counter is
+/// left to the caller.
+pub fn generate_otp(entry_info: &EntryInfo) -> Result<Zeroizing<String>> {
+    generate_otp_at(entry_info, time_since_epoch())
+}
+
+/// Generates a one time password based on the entry information and the time since the UNIX
+/// epoch in seconds
+pub fn generate_otp_at(entry_info: &EntryInfo, time_since_epoch: i64) -> Result<Zeroizing<String>> {
+    // Entries of a vault are not checked when it is read, and the code length and period must
+    // be in range for the code to be generated
+    entry_info.validate()?;
+    let code = match entry_info {
+        EntryInfo::Hotp(info) => HOTPBuilder::new()
+            .base32_key(&info.secret)
+            .hash_function(info.algo.try_into()?)
+            .output_len(info.digits.try_into()?)
+            .counter(info.counter)
+            .finalize()?
+            .generate(),
+        EntryInfo::Totp(info) => TOTPBuilder::new()
+            .timestamp(time_since_epoch)
+            .base32_key(&info.secret)
+            .hash_function(info.algo.try_into()?)
+            .output_len(info.digits.try_into()?)
+            .period(info.period.try_into()?)
+            .finalize()?
+            .generate(),
+        // Steam puts the least significant character first, libreauth the most significant
+        EntryInfo::Steam(info) => TOTPBuilder::new()
+            .timestamp(time_since_epoch)
+            .base32_key(&info.secret)
+            .output_base(STEAM_ALPHABET)
+            .output_len(5)
+            .period(30)
+            .finalize()?
+            .generate()
+            .chars()
+            .rev()
+            .collect(),
+        EntryInfo::Motp(info) => {
+            let pin = info.pin.as_deref().ok_or(eyre!("mOTP entry needs a PIN"))?;
+            let message = Zeroizing::new(format!(
+                "{}{}{}",
+                time_since_epoch / info.period as i64,
+                Zeroizing::new(hex::encode(decode_secret(&info.secret)?)).as_str(),
+                pin
+            ));
+            let digits = info.digits.try_into()?;
+            hex::encode(Md5::digest(message.as_bytes()))[..digits].to_string()
+        }
+        EntryInfo::Yandex(info) => {
+            let pin = info
+                .pin
+                .as_deref()
+                .ok_or(eyre!("Yandex entry needs a PIN"))?;
+            let secret = decode_secret(&info.secret)?;
+            let secret = &secret[..secret.len().min(YANDEX_SECRET_LEN)];
+            let mut key_hash = Zeroizing::new(
+                Sha256::new()
+                    .chain_update(pin)
+                    .chain_update(secret)
+                    .finalize()
+                    .to_vec(),
+            );
+            if key_hash[0] == 0 {
+                key_hash.remove(0);
+            }
+            let counter = time_since_epoch / info.period as i64;
+            let hash = Hmac::<Sha256>::new_from_slice(&key_hash)?
+                .chain_update(counter.to_be_bytes())
+                .finalize()
+                .into_bytes();
+            let offset = (hash[hash.len() - 1] & 0xf) as usize;
+            let mut number = u64::from_be_bytes(hash[offset..offset + 8].try_into()?);
+            number &= 0x7fff_ffff_ffff_ffff;
+            let digits: u32 = info.digits.try_into()?;
+            number %= 26u64.pow(digits);
+            let mut code = vec!['a'; digits as usize];
+            for letter in code.iter_mut().rev() {
+                *letter = (b'a' + (number % 26) as u8) as char;
+                number /= 26;
+            }
+            code.into_iter().collect()
+        }
+    };
+
+    Ok(Zeroizing::new(code))
+}
+
+/// Calculates the remaining time until the next period starts
+pub fn calculate_remaining_time(entry_info: &EntryInfo) -> Result<i32> {
+    calculate_remaining_time_at(entry_info, time_since_epoch())
+}
+
+/// Calculates the remaining time from the time since the UNIX epoch in seconds until the next
+/// period starts
+pub fn calculate_remaining_time_at(entry_info: &EntryInfo, time_since_epoch: i64) -> Result<i32> {
+    let period_length_s = entry_info
+        .period()
+        .ok_or(eyre!("HOTP entries are not time based"))? as i64;
+    if period_length_s <= 0 {
+        return Err(eyre!("Invalid period: {}", period_length_s));
+    }
+
+    Ok((period_length_s - (time_since_epoch % period_length_s)) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_zeroize_on_drop<T: ZeroizeOnDrop>() {}
+
+    #[test]
+    fn entry_infos_are_zeroized_on_drop() {
+        assert_zeroize_on_drop::<EntryInfoHotp>();
+        assert_zeroize_on_drop::<EntryInfoTotp>();
+        assert_zeroize_on_drop::<EntryInfoSteam>();
+        assert_zeroize_on_drop::<EntryInfoMotp>();
+        assert_zeroize_on_drop::<EntryInfoYandex>();
+    }
+
+    #[test]
+    fn steam_codes() {
+        let info = EntryInfo::Steam(EntryInfoSteam {
+            secret: "JRZCL47CMXVOQMNPZR2F7J4RGI".to_string(),
+            algo: HashAlgorithm::Sha1,
+            digits: 5,
+            period: 30,
+        });
+        assert_eq!(generate_otp_at(&info, 0).unwrap().as_str(), "C2F47");
+        assert_eq!(generate_otp_at(&info, 29).unwrap().as_str(), "C2F47");
+        assert_eq!(
+            generate_otp_at(&info, 1_700_000_000).unwrap().as_str(),
+            "747JR"
+        );
+    }
+
+    /// Test vectors of the Aegis app
+    #[test]
+    fn motp_codes() {
+        let info = EntryInfo::Motp(EntryInfoMotp {
+            // e3152afee62599c8 in hex
+            secret: "4MKSV7XGEWM4Q".to_string(),
+            algo: HashAlgorithm::Md5,
+            digits: 6,
+            period: 10,
+            pin: Some("1234".to_string()),
+        });
+        assert_eq!(
+            generate_otp_at(&info, 165_892_298).unwrap().as_str(),
+            "e7d8b6"
+        );
+        assert_eq!(
+            generate_otp_at(&info, 123_456_789).unwrap().as_str(),
+            "4ebfb2"
+        );
+    }
+
+    /// Test vectors of the Aegis app
+    #[test]
+    fn yandex_codes() {
+        let yandex = |secret: &str, pin: &str| {
+            EntryInfo::Yandex(EntryInfoYandex {
+                secret: secret.to_string(),
+                algo: HashAlgorithm::Sha256,
+                digits: 8,
+                period: 30,
+                pin: Some(pin.to_string()),
+            })
+        };
+        let info = yandex("LA2V6KMCGYMWWVEW64RNP3JA3IAAAAAAHTSG4HRZPI", "7586");
+        assert_eq!(
+            generate_otp_at(&info, 1_581_064_020).unwrap().as_str(),
+            "oactmacq"
+        );
+        assert_eq!(
+            generate_otp_at(&info, 1_581_090_810).unwrap().as_str(),
+            "wemdwrix"
+        );
+        let info = yandex(
+            "JBGSAU4G7IEZG6OY4UAXX62JU4AAAAAAHTSG4HXU3M",
+            "5210481216086702",
+        );
+        assert_eq!(
+            generate_otp_at(&info, 1_581_091_469).unwrap().as_str(),
+            "dfrpywob"
+        );
+        assert_eq!(
+            generate_otp_at(&info, 1_581_093_059).unwrap().as_str(),
+            "vunyprpd"
+        );
+    }
+
+    #[test]
+    fn out_of_range_entries_fail() {
+        let motp = |digits, period| {
+            EntryInfo::Motp(EntryInfoMotp {
+                secret: "JBSWY3DPEHPK3PXP".to_string(),
+                algo: HashAlgorithm::Md5,
+                digits,
+                period,
+                pin: Some("1234".to_string()),
+            })
+        };
+        assert!(generate_otp_at(&motp(6, 10), 0).is_ok());
+        assert!(generate_otp_at(&motp(40, 10), 0).is_err());
+        assert!(generate_otp_at(&motp(0, 10), 0).is_err());
+        assert!(generate_otp_at(&motp(6, 0), 0).is_err());
+        assert!(calculate_remaining_time_at(&motp(6, 0), 0).is_err());
+    }
+
+    #[test]
+    fn zeroize_wipes_secret_and_pin_but_keeps_algorithm() {
+        let mut info = EntryInfoMotp {
+            secret: "JBSWY3DPEHPK3PXP".to_string(),
+            algo: HashAlgorithm::Md5,
+            digits: 6,
+            period: 10,
+            pin: Some("1234".to_string()),
+        };
+        info.zeroize();
+        assert_eq!(info.secret, "");
+        assert_eq!(info.pin, None);
+        assert_eq!(info.digits, 0);
+        assert_eq!(info.algo, HashAlgorithm::Md5);
+    }
+}
@@ -0,0 +1,22 @@
+use color_eyre::eyre::{eyre, Result};
+use std::path::Path;
+
+/// Decode the contents of all QR codes found in the image at `path`
+pub fn decode_image(path: &Path) -> Result<Vec<String>> {
+    let image = image::open(path)
+        .map_err(|e| eyre!("Failed to read image {}: {}", path.display(), e))?
+        .to_luma8();
+    let mut prepared = rqrr::PreparedImage::prepare(image);
+    let grids = prepared.detect_grids();
+    if grids.is_empty() {
+        return Err(eyre!("No QR code found in {}", path.display()));
+    }
+    grids
+        .iter()
+        .map(|grid| {
+            grid.decode()
+                .map(|(_, content)| content)
+                .map_err(|e| eyre!("Failed to decode QR code: {}", e))
+        })
+        .collect()
+}
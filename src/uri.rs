@@ -0,0 +1,378 @@
+use base64::{engine::general_purpose, Engine as _};
+use color_eyre::eyre::{eyre, Result};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use std::{collections::HashMap, str::FromStr};
+use url::Url;
+use zeroize::Zeroizing;
+
+use crate::otp::{
+    encode_secret, normalize_secret, Entry, EntryInfo, EntryInfoHotp, EntryInfoMotp,
+    EntryInfoSteam, EntryInfoTotp, EntryInfoYandex, HashAlgorithm,
+};
+
+/// Parse the contents of a QR code, either a single Key URI or a Google Authenticator export
+pub fn parse_qr_content(content: &str) -> Result<Vec<Entry>> {
+    if content.starts_with("otpauth-migration://") {
+        parse_migration(content)
+    } else {
+        Ok(vec![parse_otpauth(content)?])
+    }
+}
+
+/// Parse a [Key URI](https://github.com/google/google-authenticator/wiki/Key-Uri-Format) like
+/// `otpauth://totp/Issuer:name?secret=...` into a new entry
+///
+/// Besides `totp` and `hotp`, the `steam`, `motp` and `yandex` types that the Aegis app exports
+/// are accepted.
+pub fn parse_otpauth(uri: &str) -> Result<Entry> {
+    let url = Url::parse(uri.trim()).map_err(|e| eyre!("Invalid URI: {}", e))?;
+    if url.scheme() != "otpauth" {
+        return Err(eyre!("Not an otpauth URI: {}", uri));
+    }
+    let entry_type = url
+        .host_str()
+        .ok_or(eyre!("URI has no type"))?
+        .to_lowercase();
+    let label = percent_decode_str(url.path().trim_start_matches('/')).decode_utf8()?;
+    let (label_issuer, name) = match label.split_once(':') {
+        Some((issuer, name)) => (Some(issuer.trim()), name.trim()),
+        None => (None, label.trim()),
+    };
+    let params: HashMap<String, String> = url
+        .query_pairs()
+        .map(|(key, value)| (key.to_lowercase(), value.into_owned()))
+        .collect();
+    let issuer = params
+        .get("issuer")
+        .map(String::as_str)
+        .or(label_issuer)
+        .unwrap_or_default();
+
+    let secret = normalize_secret(params.get("secret").ok_or(eyre!("URI has no secret"))?)?;
+    let algo = param(&params, "algorithm")?;
+    let digits = param(&params, "digits")?;
+    let period = param(&params, "period")?;
+    let info = match entry_type.as_str() {
+        "totp" => EntryInfo::Totp(EntryInfoTotp {
+            secret,
+            algo: algo.unwrap_or(HashAlgorithm::Sha1),
+            digits: digits.unwrap_or(6),
+            period: period.unwrap_or(30),
+        }),
+        "hotp" => EntryInfo::Hotp(EntryInfoHotp {
+            secret,
+            algo: algo.unwrap_or(HashAlgorithm::Sha1),
+            digits: digits.unwrap_or(6),
+            counter: param(&params, "counter")?.ok_or(eyre!("HOTP URI has no counter"))?,
+        }),
+        "steam" => EntryInfo::Steam(EntryInfoSteam {
+            secret,
+            algo: HashAlgorithm::Sha1,
+            digits: 5,
+            period: 30,
+        }),
+        "motp" => EntryInfo::Motp(EntryInfoMotp {
+            secret,
+            algo: HashAlgorithm::Md5,
+            digits: 6,
+            period: 10,
+            pin: params.get("pin").cloned(),
+        }),
+        "yandex" => EntryInfo::Yandex(EntryInfoYandex {
+            secret,
+            algo: HashAlgorithm::Sha256,
+            digits: 8,
+            period: 30,
+            pin: params.get("pin").cloned(),
+        }),
+        _ => return Err(eyre!("Unsupported URI type: {}", entry_type)),
+    };
+    info.validate()?;
+
+    Ok(Entry::new(info, issuer, name))
+}
+
+/// Parse the optional query parameter `key`
+fn param<T: FromStr>(params: &HashMap<String, String>, key: &str) -> Result<Option<T>> {
+    params
+        .get(key)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|_| eyre!("Invalid {} in URI: {}", key, value))
+        })
+        .transpose()
+}
+
+/// Format an entry as a Key URI, the inverse of [`parse_otpauth`]
+///
+/// The URI is built in a single buffer large enough for the percent encoded values, so that it
+/// is not reallocated and only the returned copy of the secret remains.
+pub fn to_otpauth(entry: &Entry) -> Zeroizing<String> {
+    let (entry_type, secret, mut params) = match &entry.info {
+        EntryInfo::Totp(info) => (
+            "totp",
+            &info.secret,
+            vec![
+                ("algorithm", info.algo.to_string()),
+                ("digits", info.digits.to_string()),
+                ("period", info.period.to_string()),
+            ],
+        ),
+        EntryInfo::Hotp(info) => (
+            "hotp",
+            &info.secret,
+            vec![
+                ("algorithm", info.algo.to_string()),
+                ("digits", info.digits.to_string()),
+                ("counter", info.counter.to_string()),
+            ],
+        ),
+        EntryInfo::Steam(info) => ("steam", &info.secret, vec![]),
+        EntryInfo::Motp(info) => ("motp", &info.secret, vec![]),
+        EntryInfo::Yandex(info) => ("yandex", &info.secret, vec![]),
+    };
+    let pin = entry.info.pin().unwrap_or_default();
+    if !entry.issuer.is_empty() {
+        params.push(("issuer", entry.issuer.clone()));
+    }
+
+    // Percent encoding at most triples the length of a value
+    let capacity = 64
+        + 3 * (secret.len() + pin.len() + 2 * entry.issuer.len() + entry.name.len())
+        + params.iter().map(|(_, value)| value.len()).sum::<usize>();
+    let mut uri = Zeroizing::new(String::with_capacity(capacity));
+    uri.push_str("otpauth://");
+    uri.push_str(entry_type);
+    uri.push('/');
+    if !entry.issuer.is_empty() {
+        uri.extend(utf8_percent_encode(&entry.issuer, NON_ALPHANUMERIC));
+        uri.push(':');
+    }
+    uri.extend(utf8_percent_encode(&entry.name, NON_ALPHANUMERIC));
+    uri.push_str("?secret=");
+    uri.extend(utf8_percent_encode(secret, NON_ALPHANUMERIC));
+    if !pin.is_empty() {
+        uri.push_str("&pin=");
+        uri.extend(utf8_percent_encode(pin, NON_ALPHANUMERIC));
+    }
+    for (key, value) in &params {
+        uri.push('&');
+        uri.push_str(key);
+        uri.push('=');
+        uri.extend(utf8_percent_encode(value, NON_ALPHANUMERIC));
+    }
+    uri
+}
+
+/// Parse a Google Authenticator `otpauth-migration://offline?data=...` export URI
+///
+/// The data is a base64 encoded protobuf `MigrationPayload` message, of which only the repeated
+/// `OtpParameters` field is used.
+pub fn parse_migration(uri: &str) -> Result<Vec<Entry>> {
+    let url = Url::parse(uri.trim()).map_err(|e| eyre!("Invalid URI: {}", e))?;
+    let data = url
+        .query_pairs()
+        .find(|(key, _)| key == "data")
+        .ok_or(eyre!("Migration URI has no data"))?
+        .1
+        // A '+' that was not percent encoded has been decoded into a space
+        .replace(' ', "+");
+    let payload = general_purpose::STANDARD.decode(data)?;
+
+    read_fields(&payload)?
+        .into_iter()
+        .filter_map(|(number, field)| match (number, field) {
+            (1, Field::Bytes(otp_parameters)) => Some(otp_parameters),
+            _ => None,
+        })
+        .map(parse_otp_parameters)
+        .collect()
+}
+
+/// Convert an `OtpParameters` message of a migration payload into a new entry
+fn parse_otp_parameters(message: &[u8]) -> Result<Entry> {
+    let mut secret = Vec::new();
+    let mut name = String::new();
+    let mut issuer = String::new();
+    let mut algo = HashAlgorithm::Sha1;
+    let mut digits = 6;
+    let mut is_hotp = false;
+    let mut counter = 0;
+    for (number, field) in read_fields(message)? {
+        match (number, field) {
+            (1, Field::Bytes(bytes)) => secret = bytes.to_vec(),
+            (2, Field::Bytes(bytes)) => name = String::from_utf8(bytes.to_vec())?,
+            (3, Field::Bytes(bytes)) => issuer = String::from_utf8(bytes.to_vec())?,
+            (4, Field::Varint(value)) => {
+                algo = match value {
+                    2 => HashAlgorithm::Sha256,
+                    3 => HashAlgorithm::Sha512,
+                    4 => HashAlgorithm::Md5,
+                    _ => HashAlgorithm::Sha1,
+                }
+            }
+            (5, Field::Varint(value)) => digits = if value == 2 { 8 } else { 6 },
+            (6, Field::Varint(value)) => is_hotp = value == 1,
+            (7, Field::Varint(value)) => counter = value,
+            _ => {}
+        }
+    }
+    // The name can still contain the issuer as in the label of a Key URI
+    if let Some((label_issuer, label_name)) = name.clone().split_once(':') {
+        if issuer.is_empty() || issuer == label_issuer.trim() {
+            issuer = label_issuer.trim().to_string();
+            name = label_name.trim().to_string();
+        }
+    }
+
+    let secret = encode_secret(&secret);
+    let info = if is_hotp {
+        EntryInfo::Hotp(EntryInfoHotp {
+            secret,
+            algo,
+            digits,
+            counter,
+        })
+    } else {
+        EntryInfo::Totp(EntryInfoTotp {
+            secret,
+            algo,
+            digits,
+            period: 30,
+        })
+    };
+    info.validate()?;
+
+    Ok(Entry::new(info, &issuer, &name))
+}
+
+/// Value of a protobuf field, fixed width fields are skipped
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+/// Read the fields of a protobuf message as pairs of field number and value
+fn read_fields(mut data: &[u8]) -> Result<Vec<(u64, Field<'_>)>> {
+    let mut fields = Vec::new();
+    while !data.is_empty() {
+        let key = read_varint(&mut data)?;
+        let field = match key & 0x7 {
+            0 => Field::Varint(read_varint(&mut data)?),
+            2 => {
+                let len = read_varint(&mut data)? as usize;
+                if len > data.len() {
+                    return Err(eyre!("Truncated protobuf message"));
+                }
+                let (bytes, rest) = data.split_at(len);
+                data = rest;
+                Field::Bytes(bytes)
+            }
+            wire_type @ (1 | 5) => {
+                let len = if wire_type == 1 { 8 } else { 4 };
+                data = data.get(len..).ok_or(eyre!("Truncated protobuf message"))?;
+                continue;
+            }
+            wire_type => return Err(eyre!("Unsupported protobuf wire type: {}", wire_type)),
+        };
+        fields.push((key >> 3, field));
+    }
+    Ok(fields)
+}
+
+/// Read a protobuf varint from the start of `data`, advancing it
+fn read_varint(data: &mut &[u8]) -> Result<u64> {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = data
+            .split_first()
+            .ok_or(eyre!("Truncated protobuf message"))?;
+        *data = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(eyre!("Invalid protobuf varint"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn otpauth_round_trip() {
+        let uri = "otpauth://totp/ACME%20Co:john%40example%2Ecom?secret=HXDMVJECJJWSRB3HWIZR4IFUGFTMXBOZ&algorithm=SHA256&digits=8&period=60&issuer=ACME%20Co";
+        let entry = parse_otpauth(uri).unwrap();
+        assert_eq!(entry.issuer, "ACME Co");
+        assert_eq!(entry.name, "john@example.com");
+        assert_eq!(to_otpauth(&entry).as_str(), uri);
+        assert_eq!(parse_otpauth(&to_otpauth(&entry)).unwrap().info, entry.info);
+    }
+
+    #[test]
+    fn otpauth_with_pin() {
+        let uri = "otpauth://motp/Mason?secret=JBSWY3DPEHPK3PXP&pin=1234";
+        let entry = parse_otpauth(uri).unwrap();
+        assert_eq!(entry.info.pin(), Some("1234"));
+        assert_eq!(to_otpauth(&entry).as_str(), uri);
+    }
+
+    /// Export of a TOTP entry with the issuer in its name, SHA256 and 8 digits, and a HOTP entry
+    /// with a counter, followed by the version, batch size, index and ID of the payload
+    const MIGRATION_URI: &str = "otpauth-migration://offline?data=CjUKCkhlbGxvId6tvu8SGEFDTUUgQ286am9obkBleGFtcGxlLmNvbRoHQUNNRSBDbyACKAIwAgovChQxMjM0NTY3ODkwMTIzNDU2Nzg5MBIFYWxpY2UaB0V4YW1wbGUgASgBMAE4rAIQARgBIAAowMQH";
+
+    fn migration_uri(payload: &[u8]) -> String {
+        format!(
+            "otpauth-migration://offline?data={}",
+            utf8_percent_encode(&general_purpose::STANDARD.encode(payload), NON_ALPHANUMERIC)
+        )
+    }
+
+    #[test]
+    fn migration_payload() {
+        let entries = parse_qr_content(MIGRATION_URI).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].issuer, "ACME Co");
+        assert_eq!(entries[0].name, "john@example.com");
+        assert_eq!(
+            entries[0].info,
+            EntryInfo::Totp(EntryInfoTotp {
+                secret: "JBSWY3DPEHPK3PXP".to_string(),
+                algo: HashAlgorithm::Sha256,
+                digits: 8,
+                period: 30,
+            })
+        );
+        assert_eq!(entries[1].issuer, "Example");
+        assert_eq!(entries[1].name, "alice");
+        assert_eq!(
+            entries[1].info,
+            EntryInfo::Hotp(EntryInfoHotp {
+                secret: "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ".to_string(),
+                algo: HashAlgorithm::Sha1,
+                digits: 6,
+                counter: 300,
+            })
+        );
+    }
+
+    #[test]
+    fn invalid_migration_payloads() {
+        let data = MIGRATION_URI.split_once("data=").unwrap().1;
+        let payload = general_purpose::STANDARD.decode(data).unwrap();
+        // Cut off in the middle of the first entry and in the varint of the last field
+        assert!(parse_migration(&migration_uri(&payload[..40])).is_err());
+        assert!(parse_migration(&migration_uri(&payload[..payload.len() - 1])).is_err());
+        // Start group wire type, and a varint longer than 64 bits
+        assert!(parse_migration(&migration_uri(&[0x0b])).is_err());
+        assert!(parse_migration(&migration_uri(&[
+            0x10, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01
+        ]))
+        .is_err());
+        assert!(parse_migration("otpauth-migration://offline?data=!!!").is_err());
+        assert!(parse_migration("otpauth-migration://offline").is_err());
+        assert!(parse_migration(&migration_uri(&[])).unwrap().is_empty());
+    }
+}
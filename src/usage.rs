@@ -0,0 +1,92 @@
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    env, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use crate::otp::{time_since_epoch, Entry};
+
+/// How often and how recently entries were selected, kept outside the vault by entry UUID
+///
+/// The file only holds UUIDs, counts and times, no secrets or even names.
+#[derive(Default, Serialize, Deserialize)]
+pub struct Usage {
+    #[serde(default)]
+    entries: HashMap<String, EntryUsage>,
+    /// File the usage is recorded in, `None` when tracking is off
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+#[derive(Default, Clone, Copy, Serialize, Deserialize)]
+pub struct EntryUsage {
+    /// Number of times the entry was selected
+    pub count: u64,
+    /// Time since the UNIX epoch of the last selection
+    pub last_used: i64,
+}
+
+/// Usage file when none is configured, in the state directory of the user
+pub fn default_path() -> Option<PathBuf> {
+    let home = || env::var_os("HOME").map(PathBuf::from);
+    let state_dir = if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        home().map(|home| home.join("Library").join("Application Support"))
+    } else {
+        env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .filter(|dir| dir.is_absolute())
+            .or_else(|| home().map(|home| home.join(".local").join("state")))
+    };
+    Some(state_dir?.join("aegis-cli").join("usage.json"))
+}
+
+impl Usage {
+    /// Read the usage file that selections are recorded in, which does not exist before the
+    /// first selection
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut usage = match fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|e| eyre!("Failed to parse usage file {}: {}", path.display(), e))?,
+            Err(e) if e.kind() == ErrorKind::NotFound => Self::default(),
+            Err(e) => return Err(eyre!("Failed to read usage file {}: {}", path.display(), e)),
+        };
+        usage.path = Some(path.to_path_buf());
+        Ok(usage)
+    }
+
+    /// Usage of the entry, zero when it was never selected
+    pub fn get(&self, uuid: &str) -> EntryUsage {
+        self.entries.get(uuid).copied().unwrap_or_default()
+    }
+
+    /// Entry that was selected last, as index into `entries`
+    pub fn most_recent(&self, entries: &[Entry]) -> Option<usize> {
+        entries
+            .iter()
+            .enumerate()
+            .map(|(index, entry)| (index, self.get(&entry.uuid).last_used))
+            .filter(|(_, last_used)| *last_used > 0)
+            .max_by_key(|(_, last_used)| *last_used)
+            .map(|(index, _)| index)
+    }
+
+    /// Count a selection of the entry now and write the usage file, when tracking is on
+    pub fn record(&mut self, uuid: &str) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let usage = self.entries.entry(uuid.to_string()).or_default();
+        usage.count += 1;
+        usage.last_used = time_since_epoch();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(self)?)
+            .map_err(|e| eyre!("Failed to write usage file {}: {}", path.display(), e))
+    }
+}
@@ -0,0 +1,621 @@
+use color_eyre::eyre::{eyre, Result};
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::otp::Entry;
+use zeroize::{Zeroize, Zeroizing};
+
+/// Cryptographic functions used to decrypt and encrypt the database with OTP entries
+///
+/// The official Aegis documentation for vault decryption and contents can be found
+/// [here](https://github.com/beemdevelopment/Aegis/blob/master/docs/vault.md#aegis-vault).
+mod crypto;
+
+pub use crypto::{benchmark_kdf, ScryptParams};
+
+/// Length of the random key in a key file
+const KEY_FILE_LEN: usize = 32;
+
+/// Trait for getting the password from the user or from the environment
+pub trait PasswordGetter {
+    /// Get the password used to decrypt the vault with [`Vault::open`]
+    fn get_password(&self) -> Result<Zeroizing<String>>;
+}
+
+/// Aegis vault backup file
+///
+/// The file and the decrypted database are kept as JSON so that fields which are not modelled
+/// by [`Entry`] (icons, groups, slot metadata, ...) survive writing the vault back to disk.
+pub struct Vault {
+    path: PathBuf,
+    contents: Value,
+    db: Value,
+    /// Master key of an encrypted vault, `None` for a plain text vault
+    master_key: Option<Zeroizing<Vec<u8>>>,
+    /// UUID of the password slot that was unlocked with the password
+    slot_uuid: Option<String>,
+}
+
+/// Master key slot in the vault header
+pub struct Slot {
+    pub uuid: String,
+    /// Type of the slot: password, biometric or raw
+    pub slot_type: &'static str,
+    /// Whether the vault was unlocked with this slot
+    pub unlocked: bool,
+}
+
+impl Vault {
+    /// Read and decrypt the vault at `path`
+    ///
+    /// The password getter is only used when the database is encrypted.
+    pub fn open(path: &Path, password_getter: &impl PasswordGetter) -> Result<Self> {
+        let mut slot_uuid = None;
+        let mut vault = Self::open_with(path, |header| {
+            let password = password_getter.get_password()?;
+            let (master_key, uuid) = crypto::decrypt_master_key(&password, header)?;
+            slot_uuid = Some(uuid);
+            Ok(master_key)
+        })?;
+        vault.slot_uuid = slot_uuid;
+        Ok(vault)
+    }
+
+    /// Read the vault at `path` and decrypt it with the key in `key_file` for a raw slot
+    pub fn open_with_key_file(path: &Path, key_file: &Path) -> Result<Self> {
+        let key = fs::read(key_file)
+            .map(Zeroizing::new)
+            .map_err(|e| eyre!("Failed to read key file {}: {}", key_file.display(), e))?;
+        if key.len() != KEY_FILE_LEN {
+            return Err(eyre!("The key file must contain {} bytes", KEY_FILE_LEN));
+        }
+        let mut slot_uuid = None;
+        let mut vault = Self::open_with(path, |header| {
+            let (master_key, uuid) = crypto::decrypt_master_key_with_key(&key, header)?;
+            slot_uuid = Some(uuid);
+            Ok(master_key)
+        })?;
+        vault.slot_uuid = slot_uuid;
+        Ok(vault)
+    }
+
+    /// Read the vault at `path` and decrypt it with an already known master key
+    pub fn open_with_master_key(path: &Path, master_key: Zeroizing<Vec<u8>>) -> Result<Self> {
+        Self::open_with(path, |_| Ok(master_key))
+    }
+
+    /// Read the vault at `path`, getting the master key from the header only when the database
+    /// is encrypted
+    fn open_with(
+        path: &Path,
+        get_master_key: impl FnOnce(&Value) -> Result<Zeroizing<Vec<u8>>>,
+    ) -> Result<Self> {
+        let contents: Value = serde_json::from_str(&Zeroizing::new(fs::read_to_string(path)?))?;
+        if contents["version"] != 1 {
+            return Err(eyre!("Unsupported vault version: {}", contents["version"]));
+        }
+        let (db, master_key) = match &contents["db"] {
+            Value::String(db) => {
+                let master_key = get_master_key(&contents["header"])?;
+                #[cfg(unix)]
+                lock_memory(&master_key);
+                let db = crypto::decrypt_database(&master_key, &contents["header"], db)?;
+                (serde_json::from_str(&db)?, Some(master_key))
+            }
+            db => (db.clone(), None),
+        };
+
+        // The models are developed for version 2 of the database but the changes after
+        // version 2 are not significant so we can still use the same models.
+        match db["version"].as_u64() {
+            Some(1..=3) => {}
+            _ => return Err(eyre!("Unsupported database version: {}", db["version"])),
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            contents,
+            db,
+            master_key,
+            slot_uuid: None,
+        })
+    }
+
+    /// Master key of an encrypted vault
+    pub fn master_key(&self) -> Option<&[u8]> {
+        self.master_key.as_deref().map(Vec::as_slice)
+    }
+
+    /// Entries in vault order, entries of unsupported types are left out
+    pub fn entries(&self) -> Vec<Entry> {
+        self.db["entries"]
+            .as_array()
+            .map(|entries| {
+                entries
+                    .iter()
+                    .filter_map(|entry| serde_json::from_value(entry.clone()).ok())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Names of the groups defined in the database by UUID, since Aegis 3
+    pub fn group_names(&self) -> HashMap<String, String> {
+        self.db["groups"]
+            .as_array()
+            .map(|groups| {
+                groups
+                    .iter()
+                    .filter_map(|group| {
+                        Some((
+                            group["uuid"].as_str()?.to_string(),
+                            group["name"].as_str()?.to_string(),
+                        ))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Overwrite the stored entry with the same UUID, keeping fields not modelled by [`Entry`]
+    pub fn update_entry(&mut self, entry: &Entry) -> Result<()> {
+        let stored = self.db["entries"]
+            .as_array_mut()
+            .and_then(|entries| {
+                entries
+                    .iter_mut()
+                    .find(|stored| stored["uuid"] == entry.uuid.as_str())
+            })
+            .and_then(Value::as_object_mut)
+            .ok_or(eyre!("Entry {} not found in vault", entry.uuid))?;
+        if let Value::Object(fields) = serde_json::to_value(entry)? {
+            for (key, value) in fields {
+                // Wipe the replaced value, like an old PIN or secret
+                if let Some(mut old) = stored.insert(key, value) {
+                    zeroize_value(&mut old);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Append a new entry to the database
+    pub fn add_entry(&mut self, entry: &Entry) -> Result<()> {
+        self.db["entries"]
+            .as_array_mut()
+            .ok_or(eyre!("No entries in database"))?
+            .push(serde_json::to_value(entry)?);
+        Ok(())
+    }
+
+    /// Remove the entry with the given UUID from the database
+    pub fn remove_entry(&mut self, uuid: &str) -> Result<()> {
+        let entries = self.db["entries"]
+            .as_array_mut()
+            .ok_or(eyre!("No entries in database"))?;
+        let index = entries
+            .iter()
+            .position(|stored| stored["uuid"] == uuid)
+            .ok_or(eyre!("Entry {} not found in vault", uuid))?;
+        zeroize_value(&mut entries.remove(index));
+        Ok(())
+    }
+
+    /// Master key slots in the vault header
+    pub fn slots(&self) -> Vec<Slot> {
+        self.contents["header"]["slots"]
+            .as_array()
+            .map(|slots| {
+                slots
+                    .iter()
+                    .map(|slot| {
+                        let uuid = slot["uuid"].as_str().unwrap_or_default().to_string();
+                        Slot {
+                            unlocked: self.slot_uuid.as_ref() == Some(&uuid),
+                            uuid,
+                            slot_type: crypto::slot_type_name(slot),
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Master key and slots of an encrypted vault, borrowed together so that the key is not
+    /// copied
+    fn slots_mut(&mut self) -> Result<(&[u8], &mut Vec<Value>)> {
+        let master_key = self
+            .master_key
+            .as_deref()
+            .ok_or(eyre!("The vault is not encrypted"))?;
+        let slots = self.contents["header"]["slots"]
+            .as_array_mut()
+            .ok_or(eyre!("No slots in header"))?;
+        Ok((master_key, slots))
+    }
+
+    /// Add a password slot for another password, returning its UUID
+    ///
+    /// The database stays encrypted with the same master key.
+    pub fn add_password_slot(&mut self, password: &str, params: ScryptParams) -> Result<String> {
+        let (master_key, slots) = self.slots_mut()?;
+        let slot = crypto::new_password_slot(password, master_key, params)?;
+        let uuid = slot["uuid"].as_str().unwrap_or_default().to_string();
+        slots.push(slot);
+        Ok(uuid)
+    }
+
+    /// Create a new key file at `key_file` with a random key and add a raw slot for it,
+    /// returning the UUID of the slot
+    pub fn add_key_file_slot(&mut self, key_file: &Path) -> Result<String> {
+        let (master_key, slots) = self.slots_mut()?;
+        let key = crypto::generate_key();
+        let slot = crypto::new_raw_slot(&key, master_key)?;
+        let uuid = slot["uuid"].as_str().unwrap_or_default().to_string();
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        options
+            .open(key_file)
+            .and_then(|mut file| file.write_all(&key))
+            .map_err(|e| eyre!("Failed to create key file {}: {}", key_file.display(), e))?;
+        slots.push(slot);
+        Ok(uuid)
+    }
+
+    /// Remove the slot with the given UUID, refusing to remove the last password slot
+    pub fn remove_slot(&mut self, uuid: &str) -> Result<()> {
+        let (_, slots) = self.slots_mut()?;
+        let index = slots
+            .iter()
+            .position(|slot| slot["uuid"] == uuid)
+            .ok_or(eyre!("Slot {} not found in vault", uuid))?;
+        let password_slots = slots.iter().filter(|s| crypto::is_password_slot(s)).count();
+        if crypto::is_password_slot(&slots[index]) && password_slots == 1 {
+            return Err(eyre!("Refusing to remove the last password slot"));
+        }
+        slots.remove(index);
+        Ok(())
+    }
+
+    /// Replace the password slot that was unlocked by a slot for the new password
+    ///
+    /// When the vault was not unlocked with a password, it must have a single password slot.
+    pub fn change_password(&mut self, password: &str, params: ScryptParams) -> Result<()> {
+        let slot_uuid = self.slot_uuid.clone();
+        let (master_key, slots) = self.slots_mut()?;
+        let mut password_slots = slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| crypto::is_password_slot(slot));
+        let unlocked = password_slots
+            .clone()
+            .find(|(_, slot)| slot["uuid"].as_str() == slot_uuid.as_deref());
+        let index = match (unlocked, password_slots.next(), password_slots.next()) {
+            (Some((index, _)), _, _) | (None, Some((index, _)), None) => Some(index),
+            _ => None,
+        }
+        .ok_or(eyre!(
+            "Unlock the vault with the password to change, it has several password slots"
+        ))?;
+        let slot = crypto::new_password_slot(password, master_key, params)?;
+        let uuid = slot["uuid"].as_str().map(String::from);
+        slots[index] = slot;
+        self.slot_uuid = uuid;
+        Ok(())
+    }
+
+    /// Encrypt the vault with a new master key, replacing all key slots by a single password slot
+    pub fn encrypt(&mut self, password: &str, params: ScryptParams) -> Result<()> {
+        let master_key = crypto::generate_key();
+        let slot = crypto::new_password_slot(password, &master_key, params)?;
+        self.slot_uuid = slot["uuid"].as_str().map(String::from);
+        self.contents["header"] = json!({ "slots": [slot], "params": null });
+        self.master_key = Some(master_key);
+        Ok(())
+    }
+
+    /// Write the vault back to disk, re-encrypting the database with the master key
+    ///
+    /// The header with the key slots is written back as it was read. The previous file is kept
+    /// next to the vault with a `.bak` extension.
+    pub fn save(&mut self) -> Result<()> {
+        let mut backup_path = self.path.as_os_str().to_owned();
+        backup_path.push(".bak");
+        fs::copy(&self.path, &backup_path)
+            .map_err(|e| eyre!("Failed to back up vault {}: {}", self.path.display(), e))?;
+        self.save_as(&self.path.clone())
+    }
+
+    /// Write the vault to `path`, encrypting the database with the master key
+    pub fn save_as(&mut self, path: &Path) -> Result<()> {
+        match &self.master_key {
+            Some(master_key) => {
+                let db = Zeroizing::new(serde_json::to_string(&self.db)?);
+                let (db, params) = crypto::encrypt_database(master_key, &db)?;
+                self.contents["header"]["params"] = params;
+                self.contents["db"] = Value::String(db);
+            }
+            None => self.contents["db"] = self.db.clone(),
+        }
+        write_atomically(
+            path,
+            &Zeroizing::new(serde_json::to_string_pretty(&self.contents)?),
+        )
+    }
+
+    /// Write the vault to `path` as a plain text vault with the database unencrypted
+    pub fn save_plain(&self, path: &Path) -> Result<()> {
+        let mut contents = self.contents.clone();
+        contents["header"] = json!({ "slots": null, "params": null });
+        contents["db"] = self.db.clone();
+        let result = write_atomically(
+            path,
+            &Zeroizing::new(serde_json::to_string_pretty(&contents)?),
+        );
+        zeroize_value(&mut contents);
+        result
+    }
+}
+
+impl Drop for Vault {
+    fn drop(&mut self) {
+        self.wipe();
+    }
+}
+
+impl Vault {
+    /// Wipe the decrypted database, the master key is wiped by [`Zeroizing`]
+    fn wipe(&mut self) {
+        zeroize_value(&mut self.db);
+        zeroize_value(&mut self.contents);
+    }
+}
+
+/// Overwrite all strings in the JSON value, which include the secrets of the entries
+fn zeroize_value(value: &mut Value) {
+    match value {
+        Value::String(string) => string.zeroize(),
+        Value::Array(values) => values.iter_mut().for_each(zeroize_value),
+        Value::Object(fields) => fields.values_mut().for_each(zeroize_value),
+        _ => {}
+    }
+}
+
+/// Keep key material from being swapped to disk, on a best effort basis
+///
+/// Returns whether the memory could be locked.
+#[cfg(unix)]
+pub fn lock_memory(key: &[u8]) -> bool {
+    // SAFETY: the pointer and length describe a live allocation
+    unsafe { libc::mlock(key.as_ptr().cast(), key.len()) == 0 }
+}
+
+/// Replace the file at `path` by writing to a temporary file next to it and renaming it
+///
+/// An existing file keeps its permissions, a new file is only accessible by the user.
+fn write_atomically(path: &Path, contents: &str) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut file = fs::File::create(&tmp_path)?;
+    match fs::metadata(path) {
+        Ok(metadata) => file.set_permissions(metadata.permissions())?,
+        #[cfg(unix)]
+        Err(_) => {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(0o600))?
+        }
+        #[cfg(not(unix))]
+        Err(_) => {}
+    }
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+        .map_err(|e| eyre!("Failed to write vault {}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::otp::EntryInfo;
+
+    struct TestPassword(&'static str);
+
+    impl PasswordGetter for TestPassword {
+        fn get_password(&self) -> Result<Zeroizing<String>> {
+            Ok(Zeroizing::new(self.0.to_string()))
+        }
+    }
+
+    /// Copy of a vault in `res` that the test can write to
+    fn vault_copy(name: &str, test: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("aegis-{}-{}-{}", test, std::process::id(), name));
+        fs::copy(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join("res").join(name),
+            &path,
+        )
+        .unwrap();
+        path
+    }
+
+    fn remove_vault(path: &Path) {
+        let mut backup_path = path.as_os_str().to_owned();
+        backup_path.push(".bak");
+        let _ = fs::remove_file(backup_path);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn open_vault_of_aegis_app() {
+        let path = vault_copy("aegis_encrypted.json", "open");
+        let vault = Vault::open(&path, &TestPassword("test")).unwrap();
+        let plain = Vault::open(
+            &Path::new(env!("CARGO_MANIFEST_DIR")).join("res/aegis_plain.json"),
+            &TestPassword("test"),
+        )
+        .unwrap();
+        assert_eq!(vault.entries(), plain.entries());
+        assert_eq!(vault.entries().len(), 7);
+        assert_eq!(vault.master_key().map(<[u8]>::len), Some(32));
+        assert!(plain.master_key().is_none());
+        assert!(vault.slots()[0].unlocked);
+        remove_vault(&path);
+    }
+
+    #[test]
+    fn save_keeps_header_slots() {
+        let path = vault_copy("aegis_encrypted.json", "save");
+        let mut vault = Vault::open(&path, &TestPassword("test")).unwrap();
+        let slots = vault.contents["header"]["slots"].clone();
+        let params = vault.contents["header"]["params"].clone();
+        vault.save().unwrap();
+
+        let reopened = Vault::open(&path, &TestPassword("test")).unwrap();
+        assert_eq!(reopened.contents["header"]["slots"], slots);
+        // The database is encrypted with a fresh nonce
+        assert_ne!(reopened.contents["header"]["params"], params);
+        assert_eq!(reopened.db, vault.db);
+        remove_vault(&path);
+    }
+
+    #[test]
+    fn encrypt_plain_export() {
+        let path = vault_copy("aegis_encrypted.json", "encrypt");
+        let plain_path = path.with_extension("plain.json");
+        let encrypted_path = path.with_extension("new.json");
+        let vault = Vault::open(&path, &TestPassword("test")).unwrap();
+        vault.save_plain(&plain_path).unwrap();
+
+        let mut plain = Vault::open(&plain_path, &TestPassword("unused")).unwrap();
+        assert!(plain.master_key().is_none());
+        assert_eq!(plain.entries(), vault.entries());
+        plain
+            .encrypt("new", ScryptParams { n: 16, r: 8, p: 1 })
+            .unwrap();
+        plain.save_as(&encrypted_path).unwrap();
+
+        let encrypted = Vault::open(&encrypted_path, &TestPassword("new")).unwrap();
+        assert_eq!(encrypted.entries(), vault.entries());
+        assert_ne!(encrypted.master_key(), vault.master_key());
+        assert_eq!(encrypted.slots().len(), 1);
+        assert!(encrypted.slots()[0].unlocked);
+        assert!(Vault::open(&encrypted_path, &TestPassword("test")).is_err());
+        for path in [path, plain_path, encrypted_path] {
+            remove_vault(&path);
+        }
+    }
+
+    #[test]
+    fn hotp_counter_is_written_back() {
+        let path = vault_copy("aegis_encrypted.json", "hotp");
+        let mut vault = Vault::open(&path, &TestPassword("test")).unwrap();
+        let mut entry = vault
+            .entries()
+            .into_iter()
+            .find(|entry| entry.issuer == "Issuu")
+            .unwrap();
+        let EntryInfo::Hotp(info) = &mut entry.info else {
+            panic!("Issuu is not an HOTP entry");
+        };
+        assert_eq!(info.counter, 1);
+        info.counter += 1;
+        vault.update_entry(&entry).unwrap();
+        vault.save().unwrap();
+
+        let master_key = Zeroizing::new(vault.master_key().unwrap().to_vec());
+        let reopened = Vault::open_with_master_key(&path, master_key).unwrap();
+        let stored = reopened
+            .entries()
+            .into_iter()
+            .find(|stored| stored.uuid == entry.uuid)
+            .unwrap();
+        assert_eq!(stored, entry);
+        // Fields not modelled by `Entry` are kept
+        let index = vault
+            .entries()
+            .iter()
+            .position(|e| e.uuid == entry.uuid)
+            .unwrap();
+        assert_eq!(
+            reopened.db["entries"][index]["icon"],
+            vault.db["entries"][index]["icon"]
+        );
+        remove_vault(&path);
+    }
+
+    fn plain_vault() -> Vault {
+        Vault {
+            path: PathBuf::from("aegis.json"),
+            contents: json!({ "version": 1, "header": { "slots": null, "params": null } }),
+            db: json!({
+                "version": 3,
+                "entries": [{
+                    "type": "totp",
+                    "uuid": "3deaa8e7-7a5d-4d43-9b5c-8d0e3b3b6c4e",
+                    "name": "Mason",
+                    "issuer": "Deno",
+                    "note": "",
+                    "icon": null,
+                    "info": { "secret": "4SJHB4GSD43FZBAI7C2HLRJGPQ", "algo": "SHA1", "digits": 6, "period": 30 }
+                }],
+                "groups": []
+            }),
+            master_key: None,
+            slot_uuid: None,
+        }
+    }
+
+    #[test]
+    fn zeroize_value_wipes_nested_strings() {
+        let mut value = json!({ "a": ["secret", { "b": "pin" }], "c": 6, "d": null });
+        zeroize_value(&mut value);
+        assert_eq!(value, json!({ "a": ["", { "b": "" }], "c": 6, "d": null }));
+    }
+
+    #[test]
+    fn wipe_clears_database_and_contents() {
+        let mut vault = plain_vault();
+        vault.wipe();
+        assert_eq!(vault.db["entries"][0]["info"]["secret"], "");
+        assert_eq!(vault.db["entries"][0]["issuer"], "");
+        assert_eq!(
+            vault.contents["header"],
+            json!({ "slots": null, "params": null })
+        );
+    }
+
+    #[test]
+    fn update_entry_keeps_unknown_fields_and_order() {
+        let mut vault = plain_vault();
+        let mut entry = vault.entries().remove(0);
+        entry.issuer = "Deno Land".to_string();
+        vault.update_entry(&entry).unwrap();
+        let stored = vault.db["entries"][0].as_object().unwrap();
+        assert_eq!(stored["issuer"], "Deno Land");
+        assert!(stored.contains_key("icon"));
+        let keys: Vec<&str> = stored.keys().map(String::as_str).take(4).collect();
+        assert_eq!(keys, ["type", "uuid", "name", "issuer"]);
+    }
+
+    #[test]
+    fn remove_entry_of_unknown_uuid_fails() {
+        let mut vault = plain_vault();
+        assert!(vault.remove_entry("unknown").is_err());
+        vault
+            .remove_entry("3deaa8e7-7a5d-4d43-9b5c-8d0e3b3b6c4e")
+            .unwrap();
+        assert_eq!(vault.db["entries"], json!([]));
+    }
+}
@@ -0,0 +1,341 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, OsRng},
+    AeadCore, Aes256Gcm, KeyInit, Nonce,
+};
+use base64::{engine::general_purpose, Engine as _};
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    mem,
+    time::{Duration, Instant},
+};
+use zeroize::Zeroizing;
+
+/// Length of the AES-GCM authentication tag, appended to the cipher text by `aes-gcm`
+const TAG_LEN: usize = 16;
+
+/// Slot types of the master key decryption slots: a raw key, a key derived from a password
+/// and a key held by the Android keystore
+const SLOT_TYPE_RAW: u64 = 0;
+const SLOT_TYPE_PASSWORD: u64 = 1;
+const SLOT_TYPE_BIOMETRIC: u64 = 2;
+
+/// Length of the salt of new password slots
+const SALT_LEN: usize = 32;
+
+/// AES-GCM encryption parameters
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyParams {
+    nonce: String,
+    tag: String,
+}
+
+/// Password slot (encrypted master key + scrypt parameters + salt)
+#[derive(Debug, Serialize, Deserialize)]
+struct PasswordSlot {
+    #[serde(default)]
+    uuid: String,
+    key: String,
+    key_params: KeyParams,
+    n: u32,
+    r: u32,
+    p: u32,
+    salt: String,
+}
+
+/// scrypt parameters of the key derived from a password
+#[derive(Debug, Clone, Copy)]
+pub struct ScryptParams {
+    /// CPU and memory cost, a power of two
+    pub n: u32,
+    /// Block size
+    pub r: u32,
+    /// Parallelization
+    pub p: u32,
+}
+
+impl Default for ScryptParams {
+    /// The parameters the Aegis app uses for new password slots
+    fn default() -> Self {
+        Self {
+            n: 1 << 15,
+            r: 8,
+            p: 1,
+        }
+    }
+}
+
+/// Raw slot (master key encrypted directly with a random key, used for key files)
+#[derive(Debug, Serialize, Deserialize)]
+struct RawSlot {
+    #[serde(default)]
+    uuid: String,
+    key: String,
+    key_params: KeyParams,
+}
+
+/// Derive a key from a password with scrypt
+fn scrypt_key(password: &[u8], salt: &[u8], params: &ScryptParams) -> Result<Zeroizing<[u8; 32]>> {
+    if params.n < 2 || !params.n.is_power_of_two() {
+        return Err(eyre!("Invalid scrypt parameter N: {}", params.n));
+    }
+    let params = scrypt::Params::new(params.n.trailing_zeros() as u8, params.r, params.p, 32)
+        .map_err(|e| eyre!("Invalid scrypt parameters: {}", e))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    scrypt::scrypt(password, salt, &params, key.as_mut())?;
+    Ok(key)
+}
+
+/// Derive the key that wraps the master key from a password
+fn derive_key(password: &[u8], slot: &PasswordSlot) -> Result<Zeroizing<[u8; 32]>> {
+    let salt = hex::decode(&slot.salt).map_err(|e| eyre!("Failed to decode salt hex: {}", e))?;
+    let params = ScryptParams {
+        n: slot.n,
+        r: slot.r,
+        p: slot.p,
+    };
+    scrypt_key(password, &salt, &params)
+}
+
+/// Time it takes to derive a key with the given parameters on this machine
+pub fn benchmark_kdf(params: &ScryptParams) -> Result<Duration> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let start = Instant::now();
+    scrypt_key(b"benchmark", &salt, params)?;
+    Ok(start.elapsed())
+}
+
+/// Decrypt AES-GCM cipher text with its detached tag
+fn decrypt(key: &[u8], params: &KeyParams, cipher_text: &[u8]) -> Result<Zeroizing<Vec<u8>>> {
+    let nonce = hex::decode(&params.nonce).map_err(|_| eyre!("Failed to decode nonce"))?;
+    let mut cipher = cipher_text.to_vec();
+    cipher.extend_from_slice(&hex::decode(&params.tag).map_err(|_| eyre!("Failed to decode tag"))?);
+    Aes256Gcm::new_from_slice(key)
+        .map_err(|_| eyre!("Invalid key length"))?
+        .decrypt(Nonce::from_slice(&nonce), cipher.as_ref())
+        .map(Zeroizing::new)
+        .map_err(|_| eyre!("Failed to decrypt"))
+}
+
+/// Encrypt with AES-GCM under a fresh nonce, returning the cipher text and the detached tag
+fn encrypt(key: &[u8], plain_text: &[u8]) -> Result<(Vec<u8>, KeyParams)> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let mut cipher = Aes256Gcm::new_from_slice(key)
+        .map_err(|_| eyre!("Invalid key length"))?
+        .encrypt(&nonce, plain_text)
+        .map_err(|_| eyre!("Failed to encrypt"))?;
+    let tag = cipher.split_off(cipher.len() - TAG_LEN);
+    let params = KeyParams {
+        nonce: hex::encode(nonce),
+        tag: hex::encode(tag),
+    };
+    Ok((cipher, params))
+}
+
+/// Whether the slot wraps the master key with a key derived from a password
+pub fn is_password_slot(slot: &Value) -> bool {
+    slot["type"].as_u64() == Some(SLOT_TYPE_PASSWORD)
+}
+
+/// Human readable type of the slot
+pub fn slot_type_name(slot: &Value) -> &'static str {
+    match slot["type"].as_u64() {
+        Some(SLOT_TYPE_RAW) => "raw",
+        Some(SLOT_TYPE_PASSWORD) => "password",
+        Some(SLOT_TYPE_BIOMETRIC) => "biometric",
+        _ => "unknown",
+    }
+}
+
+/// Decrypt the master key with the first password slot in the header that accepts the password,
+/// returning the master key and the UUID of that slot
+pub fn decrypt_master_key(password: &str, header: &Value) -> Result<(Zeroizing<Vec<u8>>, String)> {
+    let slots = header["slots"]
+        .as_array()
+        .ok_or(eyre!("No slots in header"))?;
+    // Only password based master key decryptions are supported
+    for slot in slots.iter().filter(|s| is_password_slot(s)) {
+        let slot: PasswordSlot = match serde_json::from_value(slot.clone()) {
+            Ok(slot) => slot,
+            Err(e) => {
+                eprintln!("Invalid password slot: {}", e);
+                continue;
+            }
+        };
+        let derived_key = derive_key(password.as_bytes(), &slot)?;
+        let key_cipher =
+            hex::decode(&slot.key).map_err(|_| eyre!("Failed to decode master key cipher"))?;
+        // Either the password is incorrect or the slot belongs to another password
+        if let Ok(master_key) = decrypt(derived_key.as_ref(), &slot.key_params, &key_cipher) {
+            return Ok((master_key, slot.uuid));
+        }
+    }
+
+    Err(eyre!("Failed to decrypt master key"))
+}
+
+/// Decrypt the master key with the first raw slot in the header that accepts the key,
+/// returning the master key and the UUID of that slot
+pub fn decrypt_master_key_with_key(
+    key: &[u8],
+    header: &Value,
+) -> Result<(Zeroizing<Vec<u8>>, String)> {
+    let slots = header["slots"]
+        .as_array()
+        .ok_or(eyre!("No slots in header"))?;
+    for slot in slots
+        .iter()
+        .filter(|s| s["type"].as_u64() == Some(SLOT_TYPE_RAW))
+    {
+        let Ok(slot) = serde_json::from_value::<RawSlot>(slot.clone()) else {
+            continue;
+        };
+        let key_cipher =
+            hex::decode(&slot.key).map_err(|_| eyre!("Failed to decode master key cipher"))?;
+        if let Ok(master_key) = decrypt(key, &slot.key_params, &key_cipher) {
+            return Ok((master_key, slot.uuid));
+        }
+    }
+
+    Err(eyre!("Failed to decrypt master key with the key file"))
+}
+
+/// Generate a random 256 bit key, used as master key or as key file
+pub fn generate_key() -> Zeroizing<Vec<u8>> {
+    Zeroizing::new(Aes256Gcm::generate_key(&mut OsRng).to_vec())
+}
+
+/// New password slot with the master key encrypted by a key derived from the password
+pub fn new_password_slot(password: &str, master_key: &[u8], params: ScryptParams) -> Result<Value> {
+    let mut salt = [0u8; SALT_LEN];
+    OsRng.fill_bytes(&mut salt);
+    let mut slot = PasswordSlot {
+        uuid: uuid::Uuid::new_v4().to_string(),
+        key: String::new(),
+        key_params: KeyParams {
+            nonce: String::new(),
+            tag: String::new(),
+        },
+        n: params.n,
+        r: params.r,
+        p: params.p,
+        salt: hex::encode(salt),
+    };
+    let derived_key = derive_key(password.as_bytes(), &slot)?;
+    let (key_cipher, key_params) = encrypt(derived_key.as_ref(), master_key)?;
+    slot.key = hex::encode(key_cipher);
+    slot.key_params = key_params;
+
+    let mut value = serde_json::to_value(slot)?;
+    value["type"] = SLOT_TYPE_PASSWORD.into();
+    value["repaired"] = true.into();
+    value["is_backup"] = false.into();
+    Ok(value)
+}
+
+/// New raw slot with the master key encrypted by the key
+pub fn new_raw_slot(key: &[u8], master_key: &[u8]) -> Result<Value> {
+    let (key_cipher, key_params) = encrypt(key, master_key)?;
+    let slot = RawSlot {
+        uuid: uuid::Uuid::new_v4().to_string(),
+        key: hex::encode(key_cipher),
+        key_params,
+    };
+    let mut value = serde_json::to_value(slot)?;
+    value["type"] = SLOT_TYPE_RAW.into();
+    Ok(value)
+}
+
+/// Decrypt the base64 encoded database with the master key, returning the database JSON
+pub fn decrypt_database(master_key: &[u8], header: &Value, db: &str) -> Result<Zeroizing<String>> {
+    let params: KeyParams = serde_json::from_value(header["params"].clone())
+        .map_err(|_| eyre!("No params in header"))?;
+    let db_cipher = general_purpose::STANDARD.decode(db)?;
+    let mut db_contents = decrypt(master_key, &params, &db_cipher)
+        .map_err(|e| eyre!("Failed to decrypt database: {}", e))?;
+    String::from_utf8(mem::take(&mut *db_contents))
+        .map(Zeroizing::new)
+        .map_err(|e| {
+            let error = e.utf8_error();
+            Zeroizing::new(e.into_bytes());
+            eyre!("Failed to parse database as UTF-8: {}", error)
+        })
+}
+
+/// Encrypt the database JSON with the master key, returning the base64 encoded database and
+/// the header params it was encrypted with
+pub fn encrypt_database(master_key: &[u8], db: &str) -> Result<(String, Value)> {
+    let (db_cipher, params) = encrypt(master_key, db.as_bytes())?;
+    Ok((
+        general_purpose::STANDARD.encode(db_cipher),
+        serde_json::to_value(params)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap scrypt parameters, the security of the slots doesn't matter here
+    const TEST_PARAMS: ScryptParams = ScryptParams { n: 16, r: 8, p: 1 };
+
+    fn fixture(contents: &str) -> Value {
+        serde_json::from_str(contents).unwrap()
+    }
+
+    #[test]
+    fn decrypt_vault_of_aegis_app() {
+        let vault = fixture(include_str!("../../res/aegis_encrypted.json"));
+        let plain = fixture(include_str!("../../res/aegis_plain.json"));
+
+        let (master_key, uuid) = decrypt_master_key("test", &vault["header"]).unwrap();
+        assert_eq!(uuid, "a8325752-c1be-458a-9b3e-5e0a8154d9ec");
+        let db =
+            decrypt_database(&master_key, &vault["header"], vault["db"].as_str().unwrap()).unwrap();
+        assert_eq!(fixture(&db), plain["db"]);
+    }
+
+    #[test]
+    fn wrong_password_fails() {
+        let vault = fixture(include_str!("../../res/aegis_encrypted.json"));
+        assert!(decrypt_master_key("wrong", &vault["header"]).is_err());
+    }
+
+    #[test]
+    fn password_slot_round_trip() {
+        let master_key = generate_key();
+        let slot = new_password_slot("secret", &master_key, TEST_PARAMS).unwrap();
+        let uuid = slot["uuid"].as_str().unwrap().to_string();
+        assert!(is_password_slot(&slot));
+
+        let header = serde_json::json!({ "slots": [slot] });
+        let (decrypted, slot_uuid) = decrypt_master_key("secret", &header).unwrap();
+        assert_eq!(decrypted, master_key);
+        assert_eq!(slot_uuid, uuid);
+        assert!(decrypt_master_key("other", &header).is_err());
+    }
+
+    #[test]
+    fn raw_slot_round_trip() {
+        let master_key = generate_key();
+        let key = generate_key();
+        let header = serde_json::json!({ "slots": [new_raw_slot(&key, &master_key).unwrap()] });
+        let (decrypted, _) = decrypt_master_key_with_key(&key, &header).unwrap();
+        assert_eq!(decrypted, master_key);
+        assert!(decrypt_master_key_with_key(&generate_key(), &header).is_err());
+        // A raw slot is not tried with a password
+        assert!(decrypt_master_key("secret", &header).is_err());
+    }
+
+    #[test]
+    fn database_round_trip() {
+        let master_key = generate_key();
+        let (db, params) = encrypt_database(&master_key, r#"{"version":3}"#).unwrap();
+        let header = serde_json::json!({ "params": params });
+        let decrypted = decrypt_database(&master_key, &header, &db).unwrap();
+        assert_eq!(decrypted.as_str(), r#"{"version":3}"#);
+        assert!(decrypt_database(&generate_key(), &header, &db).is_err());
+    }
+}